@@ -0,0 +1,30 @@
+//! How long it takes to spawn a trivial task and wait for its result.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use ffi_helpers::{
+    task::{CancellationToken, TaskHandle},
+    Task,
+};
+
+#[derive(Debug, Clone, Copy)]
+struct Answer;
+
+impl Task for Answer {
+    type Output = u64;
+
+    fn run(
+        &self,
+        _: &CancellationToken,
+    ) -> Result<Self::Output, anyhow::Error> {
+        Ok(black_box(42))
+    }
+}
+
+fn spawn_and_wait(c: &mut Criterion) {
+    c.bench_function("spawn and wait", |b| {
+        b.iter(|| TaskHandle::spawn(Answer).wait().unwrap())
+    });
+}
+
+criterion_group!(benches, spawn_and_wait);
+criterion_main!(benches);
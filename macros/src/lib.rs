@@ -0,0 +1,104 @@
+//! Procedural macros for the `ffi_helpers` crate.
+//!
+//! You shouldn't need to depend on this crate directly, instead enable the
+//! `macros` feature on `ffi_helpers` and use the re-exports from there.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{
+    parse_macro_input, parse_quote, Data, DeriveInput, ItemFn, ReturnType,
+};
+
+/// Wrap the body of a function in `catch_panic`, so any panics are recorded in
+/// `LAST_ERROR` and the function returns its return type's `Nullable::NULL`
+/// instead of unwinding across the FFI boundary.
+///
+/// The function's signature and attributes (e.g. `#[no_mangle]` or `unsafe`)
+/// are left untouched.
+#[proc_macro_attribute]
+pub fn catch_panics(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        let attr = proc_macro2::TokenStream::from(attr);
+        return syn::Error::new_spanned(
+            attr,
+            "catch_panics takes no arguments",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = parse_macro_input!(item as ItemFn);
+
+    let ret = match sig.output {
+        ReturnType::Default => quote!(()),
+        ReturnType::Type(_, ref ty) => quote!(#ty),
+    };
+
+    let expanded = quote! {
+        #( #attrs )*
+        #vis #sig {
+            ::ffi_helpers::panic::catch_panics_or_null(move || -> #ret #block)
+        }
+    };
+
+    expanded.into()
+}
+
+/// Implement `Nullable` for a struct where every field is `Nullable`.
+///
+/// `NULL` is the struct with each field set to its own `NULL` (i.e. all zeroes
+/// for a `#[repr(C)]` struct of integers and pointers), and `is_null()`
+/// returns `true` when every field is null. This lets you use a small struct
+/// returned by value with `catch_panic!()` and friends.
+#[proc_macro_derive(Nullable)]
+pub fn derive_nullable(input: TokenStream) -> TokenStream {
+    let DeriveInput {
+        ident,
+        mut generics,
+        data,
+        ..
+    } = parse_macro_input!(input as DeriveInput);
+
+    let fields = match data {
+        Data::Struct(s) => s.fields,
+        _ => {
+            return syn::Error::new_spanned(
+                ident,
+                "Nullable can only be derived for structs",
+            )
+            .to_compile_error()
+            .into();
+        },
+    };
+
+    let where_clause = generics.make_where_clause();
+    for field in &fields {
+        let ty = &field.ty;
+        where_clause
+            .predicates
+            .push(parse_quote!(#ty: ::ffi_helpers::Nullable));
+    }
+
+    let members: Vec<_> = fields.members().collect();
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let expanded = quote! {
+        impl #impl_generics ::ffi_helpers::Nullable for #ident #ty_generics
+            #where_clause
+        {
+            const NULL: Self =
+                #ident { #( #members: ::ffi_helpers::Nullable::NULL ),* };
+
+            fn is_null(&self) -> bool {
+                true #( && ::ffi_helpers::Nullable::is_null(&self.#members) )*
+            }
+        }
+    };
+
+    expanded.into()
+}
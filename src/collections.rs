@@ -0,0 +1,104 @@
+//! Helpers for passing collections across the FFI boundary.
+//!
+//! A `Vec<T>` is handed to C as a pointer, a length and a capacity. The
+//! capacity isn't usually interesting to the caller, but it needs to be
+//! passed back so the allocation can be freed correctly.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use ffi_helpers::collections::{free_vec_parts, vec_into_raw_parts};
+//!
+//! #[no_mangle]
+//! pub unsafe extern "C" fn primes(
+//!     len: *mut usize,
+//!     capacity: *mut usize,
+//! ) -> *mut u32 {
+//!     let (ptr, l, c) = vec_into_raw_parts(vec![2, 3, 5, 7]);
+//!     *len = l;
+//!     *capacity = c;
+//!     ptr
+//! }
+//!
+//! #[no_mangle]
+//! pub unsafe extern "C" fn primes_free(
+//!     ptr: *mut u32,
+//!     len: usize,
+//!     capacity: usize,
+//! ) {
+//!     free_vec_parts(ptr, len, capacity);
+//! }
+//!
+//! # unsafe {
+//! let (mut len, mut capacity) = (0, 0);
+//! let ptr = primes(&mut len, &mut capacity);
+//! assert_eq!(std::slice::from_raw_parts(ptr, len), &[2, 3, 5, 7]);
+//! primes_free(ptr, len, capacity);
+//! # }
+//! ```
+
+use std::mem::ManuallyDrop;
+
+/// Decompose a `Vec<T>` into its pointer, length and capacity, leaking the
+/// allocation so it can be given to C.
+///
+/// Use [`vec_from_raw_parts()`] or [`free_vec_parts()`] to clean up
+/// afterwards.
+///
+/// [`vec_from_raw_parts()`]: fn.vec_from_raw_parts.html
+/// [`free_vec_parts()`]: fn.free_vec_parts.html
+pub fn vec_into_raw_parts<T>(v: Vec<T>) -> (*mut T, usize, usize) {
+    let mut v = ManuallyDrop::new(v);
+    (v.as_mut_ptr(), v.len(), v.capacity())
+}
+
+/// Reassemble a `Vec<T>` from the parts returned by [`vec_into_raw_parts()`].
+///
+/// # Safety
+///
+/// The parts must have come from [`vec_into_raw_parts()`] and may only be
+/// used once.
+///
+/// [`vec_into_raw_parts()`]: fn.vec_into_raw_parts.html
+pub unsafe fn vec_from_raw_parts<T>(
+    ptr: *mut T,
+    len: usize,
+    capacity: usize,
+) -> Vec<T> {
+    Vec::from_raw_parts(ptr, len, capacity)
+}
+
+/// Free the parts returned by [`vec_into_raw_parts()`], dropping each item.
+///
+/// If `ptr` is null `LAST_ERROR` is updated and nothing is freed.
+///
+/// # Safety
+///
+/// See [`vec_from_raw_parts()`].
+///
+/// [`vec_into_raw_parts()`]: fn.vec_into_raw_parts.html
+/// [`vec_from_raw_parts()`]: fn.vec_from_raw_parts.html
+pub unsafe fn free_vec_parts<T>(ptr: *mut T, len: usize, capacity: usize) {
+    crate::null_pointer_check!(ptr);
+    drop(vec_from_raw_parts(ptr, len, capacity));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_a_vec_through_its_parts() {
+        let mut original = Vec::with_capacity(16);
+        original.extend(["a", "b", "c"].iter().map(|s| s.to_string()));
+
+        let (ptr, len, capacity) = vec_into_raw_parts(original);
+        assert_eq!((len, capacity), (3, 16));
+
+        let got = unsafe { vec_from_raw_parts(ptr, len, capacity) };
+        assert_eq!(got, vec!["a", "b", "c"]);
+
+        let (ptr, len, capacity) = vec_into_raw_parts(got);
+        unsafe { free_vec_parts(ptr, len, capacity) };
+    }
+}
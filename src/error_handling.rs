@@ -45,7 +45,9 @@
 //!
 //!         // then interpret the message
 //!         match bytes_written {
-//!             -1 => panic!("Our buffer wasn't big enough!"),
+//!             needed if needed < 0 => {
+//!                 panic!("Our buffer wasn't big enough, we need {}", -needed)
+//!             }
 //!             0 => panic!("There wasn't an error message... Huh?"),
 //!             len if len > 0 => {
 //!                 buffer.truncate(len as usize - 1);
@@ -78,220 +80,2168 @@
 
 use anyhow::Error;
 use libc::{c_char, c_int};
-use std::{cell::RefCell, slice};
+use std::{
+    any::TypeId,
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt::{Debug, Display},
+    marker::PhantomData,
+    mem, slice,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::{Duration, Instant},
+};
 
-use crate::nullable::Nullable;
+use crate::{
+    nullable::Nullable,
+    panic::Panic,
+    strings::{buffer_too_small, copy_str_into_buffer},
+};
 
 thread_local! {
-    static LAST_ERROR: RefCell<Option<Error>> = RefCell::new(None);
+    static LAST_ERROR: RefCell<HashMap<u64, LastError>> =
+        RefCell::new(HashMap::new());
+    /// Did the last call to `error_message_latin1()` need to replace any
+    /// characters?
+    static LATIN1_LOSSY: Cell<bool> = const { Cell::new(false) };
 }
 
-/// Clear the `LAST_ERROR`.
-pub extern "C" fn clear_last_error() { let _ = take_last_error(); }
+/// The slot used by all the functions which don't take an explicit key.
+const DEFAULT_SLOT: u64 = 0;
 
-/// Take the most recent error, clearing `LAST_ERROR` in the process.
-pub fn take_last_error() -> Option<Error> {
-    LAST_ERROR.with(|prev| prev.borrow_mut().take())
+/// The most recent error, along with the code it was reported with and any
+/// context attached by [`add_error_context()`].
+///
+/// [`add_error_context()`]: fn.add_error_context.html
+struct LastError {
+    error: Error,
+    code: i64,
+    context: Vec<(String, String)>,
 }
 
-/// Update the `thread_local` error, taking ownership of the `Error`.
-pub fn update_last_error<E: Into<Error>>(err: E) {
-    LAST_ERROR.with(|prev| *prev.borrow_mut() = Some(err.into()));
+/// The code reported by [`last_error_code()`] for errors which weren't given
+/// an explicit code.
+///
+/// [`last_error_code()`]: fn.last_error_code.html
+pub const DEFAULT_ERROR_CODE: c_int = -1;
+
+type ErrorTransform = Box<dyn Fn(Error) -> Option<Error> + Send + Sync>;
+
+static ERROR_TRANSFORM: RwLock<Option<ErrorTransform>> = RwLock::new(None);
+static ERROR_PREFIX: RwLock<String> = RwLock::new(String::new());
+static STORE_RENDERED: AtomicBool = AtomicBool::new(false);
+static CODE_MESSAGE_FORMAT: RwLock<Option<String>> = RwLock::new(None);
+
+/// The format used by [`error_message_with_code_utf8()`] unless it is
+/// overridden with [`set_code_message_format()`].
+///
+/// [`error_message_with_code_utf8()`]: fn.error_message_with_code_utf8.html
+/// [`set_code_message_format()`]: fn.set_code_message_format.html
+pub const DEFAULT_CODE_MESSAGE_FORMAT: &str = "[E{code:04}] {message}";
+static ERROR_CODES: RwLock<Vec<RegisteredCode>> = RwLock::new(Vec::new());
+static ERROR_HOOKS: RwLock<Vec<(HookId, ErrorHook)>> = RwLock::new(Vec::new());
+static ERROR_RATE_LIMIT: AtomicU32 = AtomicU32::new(0);
+static RATE_WINDOW: RwLock<Option<RateWindow>> = RwLock::new(None);
+static COALESCED_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+/// The hooks which have been run during the current one-second window.
+struct RateWindow {
+    started: Instant,
+    hooks_run: u32,
 }
 
-/// Get the length of the last error message in bytes when encoded as UTF-8,
-/// including the trailing null.
-pub fn last_error_length() -> c_int {
-    LAST_ERROR.with(|prev| {
-        prev.borrow()
-            .as_ref()
-            .map(|e| format!("{:#}", e).len() + 1)
-            .unwrap_or(0)
-    }) as c_int
+/// A function which is notified about every error stored in `LAST_ERROR`.
+pub type ErrorHook = Arc<dyn Fn(&Error) + Send + Sync>;
+
+/// An identifier returned by [`add_error_hook()`] which can be used to remove
+/// the hook again.
+///
+/// [`add_error_hook()`]: fn.add_error_hook.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HookId(u64);
+
+/// An error code registered with [`register_error_code()`].
+///
+/// [`register_error_code()`]: fn.register_error_code.html
+struct RegisteredCode {
+    type_id: TypeId,
+    matches: fn(&Error) -> bool,
+    code: c_int,
 }
 
-/// Get the length of the last error message in bytes when encoded as UTF-16,
-/// including the trailing null.
-pub fn last_error_length_utf16() -> c_int {
-    LAST_ERROR.with(|prev| {
-        prev.borrow()
-            .as_ref()
-            .map(|e| format!("{:#}", e).encode_utf16().count() + 1)
-            .unwrap_or(0)
-    }) as c_int
+/// Install a function which gets to inspect (and possibly rewrite) every
+/// error before [`update_last_error()`] stores it.
+///
+/// Returning `Some(new_err)` replaces the error, while returning `None`
+/// suppresses it entirely and leaves `LAST_ERROR` cleared. This is handy for
+/// things like redacting sensitive data from messages before they can be read
+/// by the host application.
+///
+/// Installing a transform replaces any previously installed one.
+///
+/// [`update_last_error()`]: fn.update_last_error.html
+pub fn set_error_transform(transform: ErrorTransform) {
+    *write_config(&ERROR_TRANSFORM) = Some(transform);
 }
 
-/// Peek at the most recent error and get its error message as a Rust `String`.
-pub fn error_message() -> Option<String> {
-    LAST_ERROR.with(|prev| prev.borrow().as_ref().map(|e| format!("{:#}", e)))
+/// Remove the transform installed by [`set_error_transform()`], if any.
+///
+/// [`set_error_transform()`]: fn.set_error_transform.html
+pub fn clear_error_transform() { *write_config(&ERROR_TRANSFORM) = None; }
+
+/// Set a prefix which is prepended (verbatim) to every error message, e.g.
+/// `"[mylib] "`.
+///
+/// This is useful when the host aggregates errors from several libraries and
+/// wants to know where each came from. The prefix is included in the lengths
+/// reported by [`last_error_length()`] and friends. It defaults to being
+/// empty.
+///
+/// [`last_error_length()`]: fn.last_error_length.html
+pub fn set_error_prefix(prefix: &str) {
+    *write_config(&ERROR_PREFIX) = prefix.to_string();
 }
 
-/// Peek at the most recent error and write its error message (`Display` impl)
-/// into the provided buffer as a UTF-8 encoded string.
+/// Register a function which will be called with every error passed to
+/// [`update_last_error()`] (and friends), just before it is stored.
 ///
-/// This returns the number of bytes written, or `-1` if there was an error.
-pub unsafe fn error_message_utf8(buf: *mut c_char, length: c_int) -> c_int {
-    crate::null_pointer_check!(buf);
-    let buffer = slice::from_raw_parts_mut(buf as *mut u8, length as usize);
+/// Any number of hooks can be installed, letting independent components each
+/// observe errors, and they are invoked in the order they were added. Hooks
+/// see the error after any transform installed with
+/// [`set_error_transform()`] has been applied, and aren't called for errors
+/// the transform suppressed.
+///
+/// Setting an error from inside a hook will call the hooks again, so be
+/// careful not to recurse forever.
+///
+/// [`update_last_error()`]: fn.update_last_error.html
+/// [`set_error_transform()`]: fn.set_error_transform.html
+pub fn add_error_hook(hook: ErrorHook) -> HookId {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
 
-    copy_error_into_buffer(buffer, |msg| msg.into())
+    let id = HookId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    write_config(&ERROR_HOOKS).push((id, hook));
+    id
 }
 
-/// Peek at the most recent error and write its error message (`Display` impl)
-/// into the provided buffer as a UTF-16 encoded string.
+/// Remove a hook installed by [`add_error_hook()`], returning `false` if it
+/// had already been removed.
 ///
-/// This returns the number of bytes written, or `-1` if there was an error.
-pub unsafe fn error_message_utf16(buf: *mut u16, length: c_int) -> c_int {
-    crate::null_pointer_check!(buf);
-    let buffer = slice::from_raw_parts_mut(buf, length as usize);
+/// [`add_error_hook()`]: fn.add_error_hook.html
+pub fn remove_error_hook(id: HookId) -> bool {
+    let mut hooks = write_config(&ERROR_HOOKS);
+    let before = hooks.len();
+    hooks.retain(|(hook_id, _)| *hook_id != id);
+
+    hooks.len() != before
+}
+
+/// Only run the error hooks for up to `max_per_second` errors each second,
+/// or remove the limit by passing `0` (the default).
+///
+/// Errors are still stored in `LAST_ERROR` as normal, but any errors over the
+/// limit are coalesced and won't be passed to the hooks installed with
+/// [`add_error_hook()`]. This protects things like telemetry sinks from being
+/// flooded by a buggy loop. Use [`coalesced_error_count()`] to find out how
+/// many errors the hooks missed.
+///
+/// [`add_error_hook()`]: fn.add_error_hook.html
+/// [`coalesced_error_count()`]: fn.coalesced_error_count.html
+pub fn set_error_rate_limit(max_per_second: u32) {
+    ERROR_RATE_LIMIT.store(max_per_second, Ordering::SeqCst);
+    *write_config(&RATE_WINDOW) = None;
+}
+
+/// The number of errors which weren't passed to the error hooks because of
+/// the limit set with [`set_error_rate_limit()`].
+///
+/// [`set_error_rate_limit()`]: fn.set_error_rate_limit.html
+pub fn coalesced_error_count() -> u64 {
+    COALESCED_ERRORS.load(Ordering::SeqCst)
+}
+
+/// Should the hooks be run for another error, according to the limit set
+/// with [`set_error_rate_limit()`]?
+///
+/// [`set_error_rate_limit()`]: fn.set_error_rate_limit.html
+fn within_rate_limit() -> bool {
+    let limit = ERROR_RATE_LIMIT.load(Ordering::SeqCst);
+    if limit == 0 {
+        return true;
+    }
 
-    let ret =
-        copy_error_into_buffer(buffer, |msg| msg.encode_utf16().collect());
+    let now = Instant::now();
+    let mut window = write_config(&RATE_WINDOW);
+    let window = match *window {
+        Some(ref mut w) if now - w.started < Duration::from_secs(1) => w,
+        _ => window.insert(RateWindow {
+            started: now,
+            hooks_run: 0,
+        }),
+    };
 
-    if ret > 0 {
-        // utf16 uses two bytes per character
-        ret * 2
+    if window.hooks_run < limit {
+        window.hooks_run += 1;
+        true
     } else {
-        ret
+        COALESCED_ERRORS.fetch_add(1, Ordering::SeqCst);
+        false
+    }
+}
+
+pub(crate) fn run_error_hooks(err: &Error) {
+    // clone the hooks so they can add or remove hooks without deadlocking
+    let hooks: Vec<ErrorHook> = read_config(&ERROR_HOOKS)
+        .iter()
+        .map(|(_, hook)| Arc::clone(hook))
+        .collect();
+
+    if hooks.is_empty() || !within_rate_limit() {
+        return;
+    }
+
+    for hook in hooks {
+        hook(err);
     }
 }
 
-fn copy_error_into_buffer<B, F>(buffer: &mut [B], error_msg: F) -> c_int
+/// Associate an error code with a particular type of error at runtime.
+///
+/// Whenever an error is stored without an explicit code (e.g. using
+/// [`update_last_error()`]) and it can be downcast to `E`,
+/// [`last_error_code()`] will report `code` instead of the
+/// [`DEFAULT_ERROR_CODE`]. Registering the
+/// same type twice replaces its code, and if an error matches several
+/// registered types the first one registered wins.
+///
+/// [`update_last_error()`]: fn.update_last_error.html
+/// [`last_error_code()`]: fn.last_error_code.html
+/// [`DEFAULT_ERROR_CODE`]: constant.DEFAULT_ERROR_CODE.html
+pub fn register_error_code<E>(code: c_int)
 where
-    F: FnOnce(String) -> Vec<B>,
-    B: Copy + Nullable,
+    E: Display + Debug + Send + Sync + 'static,
 {
-    let maybe_error_message: Option<Vec<B>> =
-        error_message().map(|msg| error_msg(msg));
+    let type_id = TypeId::of::<E>();
+    let mut codes = write_config(&ERROR_CODES);
 
-    let err_msg = match maybe_error_message {
-        Some(msg) => msg,
-        None => return 0,
-    };
-
-    if err_msg.len() + 1 > buffer.len() {
-        // buffer isn't big enough
-        return -1;
+    match codes
+        .iter_mut()
+        .find(|registered| registered.type_id == type_id)
+    {
+        Some(registered) => registered.code = code,
+        None => codes.push(RegisteredCode {
+            type_id,
+            matches: |err| err.is::<E>(),
+            code,
+        }),
     }
+}
 
-    buffer[..err_msg.len()].copy_from_slice(&err_msg);
-    // Make sure to add a trailing null in case people use this as a bare char*
-    buffer[err_msg.len()] = B::NULL;
+/// Remove every code registered with [`register_error_code()`].
+///
+/// [`register_error_code()`]: fn.register_error_code.html
+pub fn clear_error_codes() { write_config(&ERROR_CODES).clear(); }
 
-    (err_msg.len() + 1) as c_int
+fn registered_code(err: &Error) -> Option<c_int> {
+    read_config(&ERROR_CODES)
+        .iter()
+        .find(|registered| (registered.matches)(err))
+        .map(|registered| registered.code)
 }
 
-#[doc(hidden)]
-#[macro_export]
-macro_rules! export_c_symbol {
-    (fn $name:ident($( $arg:ident : $type:ty ),*) -> $ret:ty) => {
-        #[no_mangle]
-        pub unsafe extern "C" fn $name($( $arg : $type),*) -> $ret {
-            $crate::error_handling::$name($( $arg ),*)
-        }
-    };
-    (fn $name:ident($( $arg:ident : $type:ty ),*)) => {
-        export_c_symbol!(fn $name($( $arg : $type),*) -> ());
-    }
+/// Set the format used by [`error_message_with_code_utf8()`] to combine an
+/// error's code and message.
+///
+/// The format may contain `{message}` and `{code}` placeholders, and the code
+/// can be zero-padded to a particular width using `{code:0N}` (e.g.
+/// `{code:04}`). Anything else is copied verbatim. It defaults to
+/// [`DEFAULT_CODE_MESSAGE_FORMAT`].
+///
+/// [`error_message_with_code_utf8()`]: fn.error_message_with_code_utf8.html
+/// [`DEFAULT_CODE_MESSAGE_FORMAT`]: constant.DEFAULT_CODE_MESSAGE_FORMAT.html
+pub fn set_code_message_format(format: &str) {
+    *write_config(&CODE_MESSAGE_FORMAT) = Some(format.to_string());
 }
 
-/// As a workaround for [rust-lang/rfcs#2771][2771], you can use this macro to
-/// make sure the symbols for `ffi_helpers`'s error handling are correctly
-/// exported in your `cdylib`.
+/// Render errors as soon as they are set instead of keeping the original
+/// `Error` around.
 ///
-/// [2771]: https://github.com/rust-lang/rfcs/issues/2771
-#[macro_export]
-macro_rules! export_error_handling_functions {
-    () => {
-        #[allow(missing_docs)]
-        #[doc(hidden)]
-        pub mod __ffi_helpers_errors {
-            export_c_symbol!(fn clear_last_error());
-            export_c_symbol!(fn last_error_length() -> ::libc::c_int);
-            export_c_symbol!(fn last_error_length_utf16() -> ::libc::c_int);
-            export_c_symbol!(fn error_message_utf8(buf: *mut ::libc::c_char, length: ::libc::c_int) -> ::libc::c_int);
-            export_c_symbol!(fn error_message_utf16(buf: *mut u16, length: ::libc::c_int) -> ::libc::c_int);
-        }
-    };
+/// When enabled, every stored error is immediately converted to a
+/// [`StringError`] containing its full message (including any causes), and
+/// the original error is dropped. That means nothing borrowed by the error
+/// outlives the call which set it, but it also means you can no longer
+/// downcast the error (e.g. with [`last_error_is()`]) to find out what it
+/// was. The error's code is unaffected. This is disabled by default.
+///
+/// [`StringError`]: struct.StringError.html
+/// [`last_error_is()`]: fn.last_error_is.html
+pub fn set_store_rendered(enabled: bool) {
+    STORE_RENDERED.store(enabled, Ordering::SeqCst);
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::str;
+/// Restore every error handling setting to its default, removing any
+/// transform, prefix, hooks, or error codes which may have been configured.
+///
+/// This is mainly useful in tests and for hosts which want to re-initialize a
+/// library. It doesn't touch the `LAST_ERROR` itself.
+pub fn reset_config() {
+    clear_error_transform();
+    set_error_prefix("");
+    set_store_rendered(false);
+    *write_config(&CODE_MESSAGE_FORMAT) = None;
+    clear_error_codes();
+    write_config(&ERROR_HOOKS).clear();
+    set_error_rate_limit(0);
+}
 
-    fn clear_last_error() {
-        let _ = LAST_ERROR.with(|e| e.borrow_mut().take());
+pub(crate) fn dump_config(out: &mut String) {
+    use std::fmt::Write;
+
+    let prefix = read_config(&ERROR_PREFIX);
+    let transform = read_config(&ERROR_TRANSFORM).is_some();
+    let _ = writeln!(out, "error_handling.prefix: {:?}", *prefix);
+    let _ = writeln!(out, "error_handling.transform_installed: {}", transform);
+    let _ = writeln!(
+        out,
+        "error_handling.code_message_format: {:?}",
+        read_config(&CODE_MESSAGE_FORMAT)
+            .as_deref()
+            .unwrap_or(DEFAULT_CODE_MESSAGE_FORMAT)
+    );
+    let _ = writeln!(
+        out,
+        "error_handling.store_rendered: {}",
+        STORE_RENDERED.load(Ordering::SeqCst)
+    );
+    let _ = writeln!(
+        out,
+        "error_handling.registered_error_codes: {}",
+        read_config(&ERROR_CODES).len()
+    );
+    let _ = writeln!(
+        out,
+        "error_handling.error_hooks: {}",
+        read_config(&ERROR_HOOKS).len()
+    );
+    let _ = writeln!(
+        out,
+        "error_handling.error_rate_limit: {}",
+        ERROR_RATE_LIMIT.load(Ordering::SeqCst)
+    );
+}
+
+fn apply_error_transform(err: Error) -> Option<Error> {
+    match *read_config(&ERROR_TRANSFORM) {
+        Some(ref transform) => transform(err),
+        None => Some(err),
     }
+}
 
-    #[test]
-    fn update_the_error() {
-        clear_last_error();
+// A panic while one of the global settings is locked would poison it, and
+// unwrapping a poisoned lock would then turn every subsequent error handling
+// call into a panic. The settings are always left in a valid state, so it's
+// safe to just ignore the poisoning.
 
-        let err_msg = "An Error Occurred";
-        let e = anyhow::anyhow!(err_msg);
+pub(crate) fn read_config<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
 
-        update_last_error(e);
+pub(crate) fn write_config<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
 
-        let got_err_msg = LAST_ERROR
-            .with(|e| format!("{:#}", e.borrow_mut().take().unwrap()));
-        assert_eq!(got_err_msg, err_msg);
-    }
+/// The version of the FFI contract exposed by the error handling functions.
+///
+/// This gets bumped whenever the behaviour of an exported function changes in
+/// a way that could break an existing caller.
+pub const ABI_VERSION: u32 = 4;
 
-    #[test]
-    fn take_the_last_error() {
-        clear_last_error();
+/// Get the [`ABI_VERSION`] this library was compiled with, letting the host
+/// check it is compatible when the library is loaded.
+///
+/// [`ABI_VERSION`]: constant.ABI_VERSION.html
+pub extern "C" fn ffi_helpers_abi_version() -> u32 { ABI_VERSION }
 
-        let err_msg = "An Error Occurred";
-        let e = anyhow::anyhow!(err_msg);
-        update_last_error(e);
+/// The value returned by integer functions to indicate failure (i.e. the
+/// [`Nullable::NULL`] value for a `c_int`).
+///
+/// This and [`ffi_helpers_default_error_code()`] let generated headers refer
+/// to the sentinel values this library actually uses instead of hard-coding
+/// them. Note that the functions which copy the error message into a buffer
+/// indicate the buffer is too small by returning the *negative* of the
+/// required length, so any negative value means "too small".
+///
+/// [`Nullable::NULL`]: ../trait.Nullable.html#associatedconstant.NULL
+/// [`ffi_helpers_default_error_code()`]: fn.ffi_helpers_default_error_code.html
+pub extern "C" fn ffi_helpers_null_int() -> c_int { <c_int as Nullable>::NULL }
 
-        let got_err_msg = format!("{:#}", take_last_error().unwrap());
-        assert_eq!(got_err_msg, err_msg);
+/// The [`DEFAULT_ERROR_CODE`] reported by [`last_error_code()`] for errors
+/// which weren't given an explicit code.
+///
+/// [`DEFAULT_ERROR_CODE`]: constant.DEFAULT_ERROR_CODE.html
+/// [`last_error_code()`]: fn.last_error_code.html
+pub extern "C" fn ffi_helpers_default_error_code() -> c_int {
+    DEFAULT_ERROR_CODE
+}
+
+/// The [`COPY_NO_ERROR`] value returned when there is no message to copy.
+///
+/// [`COPY_NO_ERROR`]: constant.COPY_NO_ERROR.html
+pub extern "C" fn ffi_helpers_copy_no_error() -> c_int { COPY_NO_ERROR }
+
+/// The [`COPY_BUFFER_TOO_SMALL`] threshold for detecting a buffer which was
+/// too small.
+///
+/// [`COPY_BUFFER_TOO_SMALL`]: constant.COPY_BUFFER_TOO_SMALL.html
+pub extern "C" fn ffi_helpers_copy_buffer_too_small() -> c_int {
+    COPY_BUFFER_TOO_SMALL
+}
+
+/// The [`COPY_INVALID_LENGTH`] value returned when the caller passes in a
+/// negative buffer length.
+///
+/// [`COPY_INVALID_LENGTH`]: constant.COPY_INVALID_LENGTH.html
+pub extern "C" fn ffi_helpers_copy_invalid_length() -> c_int {
+    COPY_INVALID_LENGTH
+}
+
+/// Clear the `LAST_ERROR`.
+pub extern "C" fn clear_last_error() { let _ = take_last_error(); }
+
+/// Take the most recent error, clearing `LAST_ERROR` in the process.
+pub fn take_last_error() -> Option<Error> { take_last_error_for(DEFAULT_SLOT) }
+
+/// Take the most recent error stored under `key` by
+/// [`update_last_error_for()`], clearing it in the process.
+///
+/// Each key has its own independent slot, letting several contexts on the
+/// same thread keep track of their own errors. The functions which don't take
+/// a key all use slot `0`.
+///
+/// [`update_last_error_for()`]: fn.update_last_error_for.html
+pub fn take_last_error_for(key: u64) -> Option<Error> {
+    take_slot(key).map(|last| last.error)
+}
+
+/// Get every key which currently holds an error on this thread (including
+/// slot `0`), in ascending order.
+///
+/// This is useful for draining every context's error at shutdown.
+pub fn error_keys() -> Vec<u64> {
+    let mut keys: Vec<u64> =
+        LAST_ERROR.with(|slots| match slots.try_borrow() {
+            Ok(slots) => slots.keys().copied().collect(),
+            Err(_) => Vec::new(),
+        });
+    keys.sort_unstable();
+    keys
+}
+
+/// Get the number of keys which currently hold an error (see
+/// [`error_keys()`]).
+///
+/// [`error_keys()`]: fn.error_keys.html
+pub fn error_key_count() -> c_int { error_keys().len() as c_int }
+
+/// Write the `index`'th key returned by [`error_keys()`] to `key`.
+///
+/// This returns `1` on success and `0` if `index` is out of bounds (see
+/// [`error_key_count()`]), in which case `key` is left untouched. Every `u64`
+/// is a valid key, so the status is the only way to tell the two apart.
+///
+/// # Safety
+///
+/// `key` must point to a writable `u64`.
+///
+/// [`error_keys()`]: fn.error_keys.html
+/// [`error_key_count()`]: fn.error_key_count.html
+pub unsafe fn error_key_at(index: c_int, key: *mut u64) -> c_int {
+    crate::null_pointer_check!(key);
+
+    let found = usize::try_from(index)
+        .ok()
+        .and_then(|index| error_keys().get(index).copied());
+
+    match found {
+        Some(found) => {
+            *key = found;
+            1
+        },
+        None => 0,
     }
+}
 
-    #[test]
-    fn get_the_last_error_messages_length() {
-        clear_last_error();
+/// Take the most recent error's code and rendered message (as returned by
+/// [`last_error_code()`] and [`error_message()`]), clearing `LAST_ERROR` in
+/// the process.
+///
+/// [`last_error_code()`]: fn.last_error_code.html
+/// [`error_message()`]: fn.error_message.html
+pub fn take_last_error_parts() -> Option<(c_int, String)> {
+    take_slot(DEFAULT_SLOT)
+        .map(|last| (last.code as c_int, render_error(&last.error)))
+}
 
-        let err_msg = "Some Context: An Error Occurred";
-        let should_be = err_msg.len() + 1;
+/// Get the code associated with the most recent error.
+///
+/// This is `0` if there is no error and [`DEFAULT_ERROR_CODE`] if the error
+/// wasn't set using [`update_last_error_with_code()`].
+///
+/// Codes set using [`update_last_error_with_code64()`] are truncated to their
+/// lower 32 bits, use [`last_error_code64()`] to get the full value.
+///
+/// [`DEFAULT_ERROR_CODE`]: constant.DEFAULT_ERROR_CODE.html
+/// [`update_last_error_with_code()`]: fn.update_last_error_with_code.html
+/// [`update_last_error_with_code64()`]: fn.update_last_error_with_code64.html
+/// [`last_error_code64()`]: fn.last_error_code64.html
+pub fn last_error_code() -> c_int { last_error_code64() as c_int }
 
-        let e = anyhow::anyhow!("An Error Occurred").context("Some Context");
-        update_last_error(e);
+/// Get the full 64-bit code associated with the most recent error.
+///
+/// This is the same as [`last_error_code()`], except codes set using
+/// [`update_last_error_with_code64()`] aren't truncated. Codes set with a
+/// `c_int` are sign-extended, so [`DEFAULT_ERROR_CODE`] is still `-1`.
+///
+/// [`last_error_code()`]: fn.last_error_code.html
+/// [`update_last_error_with_code64()`]: fn.update_last_error_with_code64.html
+/// [`DEFAULT_ERROR_CODE`]: constant.DEFAULT_ERROR_CODE.html
+pub fn last_error_code64() -> i64 {
+    peek_slot(DEFAULT_SLOT, |last| last.map_or(0, |last| last.code))
+}
 
-        // Get a valid error message's length
-        let got = last_error_length();
-        assert_eq!(got, should_be as _);
+/// Inspect the most recent error without taking or cloning it.
+///
+/// The closure is given a reference to the error (or `None` if there isn't
+/// one) and whatever it returns is passed back to the caller. `LAST_ERROR`
+/// is borrowed for the duration of the call, so any errors set from inside
+/// the closure will be dropped.
+pub fn with_last_error<F, R>(func: F) -> R
+where
+    F: FnOnce(Option<&Error>) -> R,
+{
+    peek_slot(DEFAULT_SLOT, |last| func(last.map(|last| &last.error)))
+}
 
-        // Then clear the error message and make sure we get 0
-        clear_last_error();
-        let got = last_error_length();
-        assert_eq!(got, 0);
+/// Is the most recent error of type `E`?
+pub fn last_error_is<E>() -> bool
+where
+    E: Display + Debug + Send + Sync + 'static,
+{
+    peek_slot(DEFAULT_SLOT, |last| {
+        last.is_some_and(|last| last.error.is::<E>())
+    })
+}
+
+/// Was the most recent error caused by a panic?
+///
+/// This returns `1` if the last error is a [`Panic`] and `0` otherwise, which
+/// lets callers distinguish bugs from "normal" errors.
+///
+/// [`Panic`]: ../panic/struct.Panic.html
+pub fn last_error_is_panic() -> c_int {
+    if last_error_is::<Panic>() {
+        1
+    } else {
+        0
     }
+}
 
-    #[test]
-    fn write_the_last_error_message_into_a_buffer() {
-        clear_last_error();
+/// Take the most recent error, trying to downcast it to a concrete type.
+///
+/// If the error isn't an `E` it is handed back (`LAST_ERROR` is still
+/// cleared). You'll get `Err(None)` if there was no error.
+pub fn take_last_error_as<E>() -> Result<E, Option<Error>>
+where
+    E: Display + Debug + Send + Sync + 'static,
+{
+    match take_last_error() {
+        Some(e) => e.downcast().map_err(Some),
+        None => Err(None),
+    }
+}
 
-        let expected = "Some Context: An Error Occurred";
+/// Update the `thread_local` error, taking ownership of the `Error`.
+///
+/// If an error transform has been installed with [`set_error_transform()`]
+/// it is applied first, possibly replacing or suppressing the error.
+///
+/// [`set_error_transform()`]: fn.set_error_transform.html
+pub fn update_last_error<E: Into<Error>>(err: E) {
+    update_last_error_with_code(err, DEFAULT_ERROR_CODE);
+}
 
-        let e = anyhow::anyhow!("An Error Occurred").context("Some Context");
-        update_last_error(e);
+/// Update the `thread_local` error, associating it with an error code which
+/// can later be retrieved with [`last_error_code()`].
+///
+/// The code should be non-zero, because `0` is used to indicate there is no
+/// error.
+///
+/// [`last_error_code()`]: fn.last_error_code.html
+pub fn update_last_error_with_code<E: Into<Error>>(err: E, code: c_int) {
+    update_slot(DEFAULT_SLOT, err.into(), code.into());
+}
 
-        let mut buffer: Vec<u8> = vec![0; 40];
-        let bytes_written = unsafe {
-            error_message_utf8(
-                buffer.as_mut_ptr() as *mut c_char,
-                buffer.len() as _,
-            )
+/// Like [`update_last_error_with_code()`], but for hosts whose error codes
+/// need all 64 bits (e.g. because they encode a category in the upper bits).
+///
+/// Use [`last_error_code64()`] to read the code back, [`last_error_code()`]
+/// will only see the lower 32 bits.
+///
+/// [`update_last_error_with_code()`]: fn.update_last_error_with_code.html
+/// [`last_error_code64()`]: fn.last_error_code64.html
+/// [`last_error_code()`]: fn.last_error_code.html
+pub fn update_last_error_with_code64<E: Into<Error>>(err: E, code: i64) {
+    update_slot(DEFAULT_SLOT, err.into(), code);
+}
+
+/// Update the error stored under `key`, leaving every other slot untouched.
+///
+/// See [`take_last_error_for()`] for more.
+///
+/// [`take_last_error_for()`]: fn.take_last_error_for.html
+pub fn update_last_error_for<E: Into<Error>>(key: u64, err: E) {
+    update_slot(key, err.into(), DEFAULT_ERROR_CODE.into());
+}
+
+fn update_slot(key: u64, err: Error, code: i64) {
+    let last = apply_error_transform(err).map(|error| {
+        run_error_hooks(&error);
+
+        let code = if code == i64::from(DEFAULT_ERROR_CODE) {
+            registered_code(&error).map_or(code, i64::from)
+        } else {
+            code
         };
 
-        assert!(bytes_written > 0);
-        assert_eq!(bytes_written as usize, expected.len() + 1);
+        LastError {
+            error: render_if_requested(error),
+            code,
+            context: Vec::new(),
+        }
+    });
 
-        let msg =
-            str::from_utf8(&buffer[..bytes_written as usize - 1]).unwrap();
-        assert_eq!(msg, expected);
+    let previous = LAST_ERROR.with(|slots| {
+        let mut slots = slots.try_borrow_mut().ok()?;
+
+        match last {
+            Some(last) => slots.insert(key, last),
+            None => slots.remove(&key),
+        }
+    });
+
+    // the previous error gets dropped after the borrow is released, in case
+    // its destructor wants to touch LAST_ERROR
+    drop(previous);
+}
+
+/// Attach a key/value pair to the most recent error, giving the host
+/// structured diagnostics (e.g. a request ID) without parsing the message.
+///
+/// The context is cleared whenever a new error is set, and nothing happens if
+/// there is no error. The host can read it back using
+/// [`error_context_count()`] and [`error_context_pair_utf8()`].
+///
+/// [`error_context_count()`]: fn.error_context_count.html
+/// [`error_context_pair_utf8()`]: fn.error_context_pair_utf8.html
+pub fn add_error_context(key: &str, value: &str) {
+    LAST_ERROR.with(|slots| {
+        if let Ok(mut slots) = slots.try_borrow_mut() {
+            if let Some(last) = slots.get_mut(&DEFAULT_SLOT) {
+                last.context.push((key.to_string(), value.to_string()));
+            }
+        }
+    });
+}
+
+/// Get the number of key/value pairs attached to the most recent error with
+/// [`add_error_context()`].
+///
+/// [`add_error_context()`]: fn.add_error_context.html
+pub fn error_context_count() -> c_int {
+    peek_slot(DEFAULT_SLOT, |last| {
+        last.map_or(0, |last| last.context.len() as c_int)
+    })
+}
+
+fn render_if_requested(err: Error) -> Error {
+    if !STORE_RENDERED.load(Ordering::SeqCst) {
+        return err;
+    }
+
+    let message = format!("{:#}", err);
+    // drop the original before anything is stored, in case its destructor
+    // wants to touch LAST_ERROR
+    drop(err);
+    Error::new(StringError { message })
+}
+
+// The error functions can be re-entered while LAST_ERROR is borrowed (e.g. by
+// an error's Display impl while we're rendering the message). Panicking here
+// would unwind across the FFI boundary, so if the slots can't be borrowed
+// updates are dropped and they are treated as empty.
+
+fn take_slot(key: u64) -> Option<LastError> {
+    LAST_ERROR.with(|slots| slots.try_borrow_mut().ok()?.remove(&key))
+}
+
+fn peek_slot<F, T>(key: u64, func: F) -> T
+where
+    F: FnOnce(Option<&LastError>) -> T,
+{
+    LAST_ERROR.with(|slots| match slots.try_borrow() {
+        Ok(slots) => func(slots.get(&key)),
+        Err(_) => func(None),
+    })
+}
+
+/// Set the `thread_local` error to a plain message.
+///
+/// This is a lightweight alternative to `update_last_error(anyhow!(...))` for
+/// the common case where all you have is a message. The error is stored as a
+/// [`StringError`] with the [`DEFAULT_ERROR_CODE`].
+///
+/// [`StringError`]: struct.StringError.html
+/// [`DEFAULT_ERROR_CODE`]: constant.DEFAULT_ERROR_CODE.html
+pub fn set_last_error_str<S: Into<String>>(msg: S) {
+    update_last_error(StringError {
+        message: msg.into(),
+    });
+}
+
+/// Let the host set the `thread_local` error to a UTF-16 encoded message
+/// (e.g. from a Windows API), like [`set_last_error_str()`].
+///
+/// `length` is measured in `u16`s and shouldn't include a trailing null.
+/// This returns `0` on success. If `msg` is null, `length` is negative, or
+/// the message isn't valid UTF-16, `-1` is returned and `LAST_ERROR` is left
+/// untouched.
+///
+/// # Safety
+///
+/// `msg` must point to at least `length` readable `u16`s.
+///
+/// [`set_last_error_str()`]: fn.set_last_error_str.html
+pub unsafe fn set_last_error_message_utf16(
+    msg: *const u16,
+    length: c_int,
+) -> c_int {
+    if msg.is_null() || length < 0 {
+        return -1;
+    }
+
+    let msg = slice::from_raw_parts(msg, length as usize);
+    match String::from_utf16(msg) {
+        Ok(msg) => {
+            set_last_error_str(msg);
+            0
+        },
+        Err(_) => -1,
+    }
+}
+
+/// An error which is nothing more than a message, as set by
+/// [`set_last_error_str()`].
+///
+/// [`set_last_error_str()`]: fn.set_last_error_str.html
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("{message}")]
+pub struct StringError {
+    /// The error message.
+    pub message: String,
+}
+
+/// Create an [`ErrorGuard`] which will clear `LAST_ERROR` when it goes out of
+/// scope.
+///
+/// [`ErrorGuard`]: struct.ErrorGuard.html
+pub fn error_guard() -> ErrorGuard {
+    ErrorGuard {
+        _not_send: PhantomData,
+    }
+}
+
+/// A guard which clears `LAST_ERROR` when dropped, making sure a stale error
+/// can't leak into the next call (even if you return early).
+///
+/// Because `LAST_ERROR` is thread-local, the guard can't be sent to another
+/// thread.
+#[must_use = "The error is cleared as soon as the guard is dropped"]
+#[derive(Debug)]
+pub struct ErrorGuard {
+    _not_send: PhantomData<*const ()>,
+}
+
+impl ErrorGuard {
+    /// Take the most recent error, if there is one.
+    pub fn take(&self) -> Option<Error> { take_last_error() }
+}
+
+impl Drop for ErrorGuard {
+    fn drop(&mut self) { clear_last_error(); }
+}
+
+/// Move every error on the current thread (including the ones stored with
+/// [`update_last_error_for()`]) into an [`ErrorScope`], leaving `LAST_ERROR`
+/// empty.
+///
+/// `LAST_ERROR` is thread-local, so an async runtime which moves a future to
+/// another worker thread at an `.await` would otherwise lose its errors.
+/// Call this before the `.await` and pass the scope to [`exit_error_scope()`]
+/// afterwards to restore them on whichever thread the future resumes on.
+///
+/// [`update_last_error_for()`]: fn.update_last_error_for.html
+/// [`ErrorScope`]: struct.ErrorScope.html
+/// [`exit_error_scope()`]: fn.exit_error_scope.html
+pub fn enter_error_scope() -> ErrorScope {
+    let slots = LAST_ERROR
+        .with(|slots| slots.try_borrow_mut().map(|mut s| mem::take(&mut *s)))
+        .unwrap_or_default();
+
+    ErrorScope { slots }
+}
+
+/// Restore the errors captured by [`enter_error_scope()`] on the current
+/// thread, replacing any errors which are already there.
+///
+/// [`enter_error_scope()`]: fn.enter_error_scope.html
+pub fn exit_error_scope(scope: ErrorScope) {
+    let previous = LAST_ERROR.with(|slots| match slots.try_borrow_mut() {
+        Ok(mut slots) => mem::replace(&mut *slots, scope.slots),
+        Err(_) => scope.slots,
+    });
+
+    // the previous errors get dropped after the borrow is released, in case
+    // their destructors want to touch LAST_ERROR
+    drop(previous);
+}
+
+/// A snapshot of a thread's errors which can be sent to another thread,
+/// created by [`enter_error_scope()`].
+///
+/// [`enter_error_scope()`]: fn.enter_error_scope.html
+#[must_use = "Pass the scope to exit_error_scope() to restore the errors"]
+pub struct ErrorScope {
+    slots: HashMap<u64, LastError>,
+}
+
+impl Debug for ErrorScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ErrorScope")
+            .field("errors", &self.slots.len())
+            .finish()
+    }
+}
+
+/// Get the length of the last error message in bytes when encoded as UTF-8,
+/// including the trailing null.
+pub fn last_error_length() -> c_int { error_length_in::<Utf8>() }
+
+/// Get the length of the last error message in `u16`s when encoded as
+/// UTF-16, including the trailing null.
+pub fn last_error_length_utf16() -> c_int { error_length_in::<Utf16>() }
+
+/// Selects UTF-8 in [`last_error_length_encoding()`].
+///
+/// [`last_error_length_encoding()`]: fn.last_error_length_encoding.html
+pub const ENCODING_UTF8: c_int = 0;
+/// Selects UTF-16 in [`last_error_length_encoding()`].
+///
+/// [`last_error_length_encoding()`]: fn.last_error_length_encoding.html
+pub const ENCODING_UTF16: c_int = 1;
+/// Selects UTF-32 in [`last_error_length_encoding()`].
+///
+/// [`last_error_length_encoding()`]: fn.last_error_length_encoding.html
+pub const ENCODING_UTF32: c_int = 2;
+
+/// Get the length of the last error message in code units of the selected
+/// encoding (see [`ENCODING_UTF8`], [`ENCODING_UTF16`] and
+/// [`ENCODING_UTF32`]), including the trailing null.
+///
+/// This returns `-1` if the encoding isn't recognised.
+///
+/// [`ENCODING_UTF8`]: constant.ENCODING_UTF8.html
+/// [`ENCODING_UTF16`]: constant.ENCODING_UTF16.html
+/// [`ENCODING_UTF32`]: constant.ENCODING_UTF32.html
+pub fn last_error_length_encoding(encoding: c_int) -> c_int {
+    match encoding {
+        ENCODING_UTF8 => error_length_in::<Utf8>(),
+        ENCODING_UTF16 => error_length_in::<Utf16>(),
+        ENCODING_UTF32 => error_length_in::<Utf32>(),
+        _ => -1,
+    }
+}
+
+/// A text encoding an error message can be measured in.
+trait Encoding {
+    /// The number of code units needed to encode `msg`.
+    fn encoded_len(msg: &str) -> usize;
+}
+
+struct Utf8;
+
+impl Encoding for Utf8 {
+    fn encoded_len(msg: &str) -> usize { msg.len() }
+}
+
+struct Utf16;
+
+impl Encoding for Utf16 {
+    fn encoded_len(msg: &str) -> usize { msg.encode_utf16().count() }
+}
+
+struct Utf32;
+
+impl Encoding for Utf32 {
+    fn encoded_len(msg: &str) -> usize { msg.chars().count() }
+}
+
+fn error_length_in<E: Encoding>() -> c_int {
+    error_message()
+        .map(|msg| E::encoded_len(&msg) + 1)
+        .unwrap_or(0) as c_int
+}
+
+/// Peek at the most recent error and get its error message as a Rust `String`.
+///
+/// The message will start with the prefix set by [`set_error_prefix()`], if
+/// any.
+///
+/// [`set_error_prefix()`]: fn.set_error_prefix.html
+pub fn error_message() -> Option<String> { error_message_for(DEFAULT_SLOT) }
+
+fn error_message_for(key: u64) -> Option<String> {
+    peek_slot(key, |last| last.map(|last| render_error(&last.error)))
+}
+
+fn render_error(e: &Error) -> String {
+    format!("{}{:#}", *read_config(&ERROR_PREFIX), e)
+}
+
+/// Returned by the functions which copy a message into a buffer (e.g.
+/// [`error_message_utf8()`]) when there is no error to copy.
+///
+/// A positive return value is the number of bytes written. See
+/// [`COPY_BUFFER_TOO_SMALL`] and [`COPY_INVALID_LENGTH`] for the negative
+/// return values.
+///
+/// [`error_message_utf8()`]: fn.error_message_utf8.html
+/// [`COPY_BUFFER_TOO_SMALL`]: constant.COPY_BUFFER_TOO_SMALL.html
+/// [`COPY_INVALID_LENGTH`]: constant.COPY_INVALID_LENGTH.html
+pub const COPY_NO_ERROR: c_int = 0;
+
+/// The largest value returned by the functions which copy a message into a
+/// buffer when the buffer is too small.
+///
+/// Instead of a single "too small" value, these functions return the
+/// negative of the required length, so `-42` means you need a buffer of 42
+/// bytes. That means the buffer was too small if the result is less than or
+/// equal to `COPY_BUFFER_TOO_SMALL` and isn't [`COPY_INVALID_LENGTH`].
+///
+/// [`COPY_INVALID_LENGTH`]: constant.COPY_INVALID_LENGTH.html
+pub const COPY_BUFFER_TOO_SMALL: c_int = -1;
+
+/// Returned by the functions which copy a message into a buffer when the
+/// caller passes in a negative length.
+///
+/// This can never be confused with a required length because those are
+/// capped at `c_int::MAX`. See [`COPY_NO_ERROR`] for the other return values.
+///
+/// [`COPY_NO_ERROR`]: constant.COPY_NO_ERROR.html
+pub const COPY_INVALID_LENGTH: c_int = c_int::MIN;
+
+/// Peek at the most recent error and write its error message (`Display` impl)
+/// into the provided buffer as a UTF-8 encoded string.
+///
+/// This returns the number of bytes written (including the trailing null). If
+/// the buffer is too small, the negative of the required length is returned
+/// instead, so `-42` means you need a buffer of 42 bytes. A negative `length`
+/// is rejected with [`COPY_INVALID_LENGTH`], without touching the buffer.
+///
+/// # Safety
+///
+/// `buf` must point to a writable buffer of at least `length` bytes.
+pub unsafe fn error_message_utf8(buf: *mut c_char, length: c_int) -> c_int {
+    crate::null_pointer_check!(buf);
+    let buffer = match buffer_from_raw(buf as *mut u8, length) {
+        Some(buffer) => buffer,
+        None => return COPY_INVALID_LENGTH,
+    };
+
+    copy_error_into_buffer(buffer, true, error_message(), |msg| {
+        msg.as_bytes().into()
+    })
+}
+
+/// Like [`error_message_utf8()`], but the message is combined with the error's
+/// code using the format set by [`set_code_message_format()`] (e.g.
+/// `"[E0042] File not found"`).
+///
+/// Errors which weren't given a code are written without one, exactly like
+/// [`error_message_utf8()`].
+///
+/// # Safety
+///
+/// `buf` must point to a writable buffer of at least `length` bytes.
+///
+/// [`error_message_utf8()`]: fn.error_message_utf8.html
+/// [`set_code_message_format()`]: fn.set_code_message_format.html
+pub unsafe fn error_message_with_code_utf8(
+    buf: *mut c_char,
+    length: c_int,
+) -> c_int {
+    crate::null_pointer_check!(buf);
+    let buffer = match buffer_from_raw(buf as *mut u8, length) {
+        Some(buffer) => buffer,
+        None => return COPY_INVALID_LENGTH,
+    };
+
+    let message = peek_slot(DEFAULT_SLOT, |last| {
+        last.map(|last| {
+            let message = render_error(&last.error);
+            if last.code == i64::from(DEFAULT_ERROR_CODE) {
+                message
+            } else {
+                format_code_message(last.code, &message)
+            }
+        })
+    });
+
+    copy_error_into_buffer(buffer, true, message, |msg| msg.as_bytes().into())
+}
+
+fn format_code_message(code: i64, message: &str) -> String {
+    let format = read_config(&CODE_MESSAGE_FORMAT);
+    let mut rest = format.as_deref().unwrap_or(DEFAULT_CODE_MESSAGE_FORMAT);
+    let mut formatted = String::new();
+
+    while let Some(start) = rest.find('{') {
+        formatted.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let end = match rest.find('}') {
+            Some(end) => end,
+            None => break,
+        };
+
+        match &rest[1..end] {
+            "message" => formatted.push_str(message),
+            "code" => formatted.push_str(&code.to_string()),
+            placeholder => {
+                let width = placeholder
+                    .strip_prefix("code:0")
+                    .map(|width| width.parse::<usize>());
+
+                match width {
+                    Some(Ok(width)) => {
+                        formatted.push_str(&format!("{:0width$}", code))
+                    },
+                    _ => formatted.push_str(&rest[..=end]),
+                }
+            },
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    formatted.push_str(rest);
+    formatted
+}
+
+/// Write the `index`'th key/value pair attached to the most recent error into
+/// the provided buffers as null-terminated UTF-8 strings.
+///
+/// This returns `1` on success and `0` if there is no pair at `index` (see
+/// [`error_context_count()`]). If either buffer is too small nothing is
+/// written and the negative of the length required to hold both the key and
+/// the value is returned instead, so `-42` means both buffers need to be at
+/// least 42 bytes. A negative length is rejected with
+/// [`COPY_INVALID_LENGTH`].
+///
+/// # Safety
+///
+/// `key_buf` and `value_buf` must point to writable buffers of at least
+/// `key_length` and `value_length` bytes, respectively.
+///
+/// [`error_context_count()`]: fn.error_context_count.html
+pub unsafe fn error_context_pair_utf8(
+    index: c_int,
+    key_buf: *mut c_char,
+    key_length: c_int,
+    value_buf: *mut c_char,
+    value_length: c_int,
+) -> c_int {
+    crate::null_pointer_check!(key_buf);
+    crate::null_pointer_check!(value_buf);
+    let (key_buffer, value_buffer) = match (
+        buffer_from_raw(key_buf as *mut u8, key_length),
+        buffer_from_raw(value_buf as *mut u8, value_length),
+    ) {
+        (Some(key_buffer), Some(value_buffer)) => (key_buffer, value_buffer),
+        _ => return COPY_INVALID_LENGTH,
+    };
+
+    let pair = peek_slot(DEFAULT_SLOT, |last| {
+        last.and_then(|last| last.context.get(usize::try_from(index).ok()?))
+            .cloned()
+    });
+    let (key, value) = match pair {
+        Some(pair) => pair,
+        None => return 0,
+    };
+
+    let (key_required, value_required) = (key.len() + 1, value.len() + 1);
+    if key_required > key_buffer.len() || value_required > value_buffer.len() {
+        return buffer_too_small(key_required.max(value_required));
+    }
+
+    copy_str_into_buffer(&key, key_buffer, true, |s| s.as_bytes().into());
+    copy_str_into_buffer(&value, value_buffer, true, |s| s.as_bytes().into());
+
+    1
+}
+
+/// Like [`error_message_utf8()`], but for a fixed-size array where the length
+/// is known at compile time.
+///
+/// This returns the number of bytes written (including the trailing null),
+/// or the negative of the required length if the message doesn't fit, in
+/// which case the array is left untouched.
+///
+/// # Safety
+///
+/// `buf` must point to a valid, writable array.
+///
+/// [`error_message_utf8()`]: fn.error_message_utf8.html
+pub unsafe fn error_message_fixed<const N: usize>(
+    buf: *mut [c_char; N],
+) -> c_int {
+    crate::null_pointer_check!(buf);
+    let buffer = &mut *(buf as *mut [u8; N]);
+
+    copy_error_into_buffer(buffer, true, error_message(), |msg| {
+        msg.as_bytes().into()
+    })
+}
+
+/// Like [`error_message_utf8()`], but for the error stored under `key` by
+/// [`update_last_error_for()`].
+///
+/// # Safety
+///
+/// `buf` must point to a writable buffer of at least `length` bytes.
+///
+/// [`error_message_utf8()`]: fn.error_message_utf8.html
+/// [`update_last_error_for()`]: fn.update_last_error_for.html
+pub unsafe fn error_message_for_utf8(
+    key: u64,
+    buf: *mut c_char,
+    length: c_int,
+) -> c_int {
+    crate::null_pointer_check!(buf);
+    let buffer = match buffer_from_raw(buf as *mut u8, length) {
+        Some(buffer) => buffer,
+        None => return COPY_INVALID_LENGTH,
+    };
+
+    copy_error_into_buffer(buffer, true, error_message_for(key), |msg| {
+        msg.as_bytes().into()
+    })
+}
+
+/// Write the message for every key which currently holds an error into the
+/// provided buffer as a null-terminated JSON object (e.g.
+/// `{"0":"File not found","3":"Timed out"}`).
+///
+/// This lets the caller read the full error state with a single call instead
+/// of one [`error_message_for_utf8()`] call per key. The errors aren't
+/// cleared, and the return value follows the same conventions as
+/// [`error_message_utf8()`] (`0` means no keys hold an error).
+///
+/// This requires the `serde` feature, so it isn't exported by
+/// [`export_error_handling_functions!()`].
+///
+/// # Safety
+///
+/// `buf` must point to a writable buffer of at least `length` bytes.
+///
+/// [`error_message_for_utf8()`]: fn.error_message_for_utf8.html
+/// [`error_message_utf8()`]: fn.error_message_utf8.html
+/// [`export_error_handling_functions!()`]: ../macro.export_error_handling_functions.html
+#[cfg(feature = "serde")]
+pub unsafe fn all_error_messages_json(
+    buf: *mut c_char,
+    length: c_int,
+) -> c_int {
+    crate::null_pointer_check!(buf);
+    let buffer = match buffer_from_raw(buf as *mut u8, length) {
+        Some(buffer) => buffer,
+        None => return COPY_INVALID_LENGTH,
+    };
+
+    let messages: std::collections::BTreeMap<u64, String> = error_keys()
+        .into_iter()
+        .filter_map(|key| Some((key, error_message_for(key)?)))
+        .collect();
+    let json = if messages.is_empty() {
+        None
+    } else {
+        serde_json::to_string(&messages).ok()
+    };
+
+    copy_error_into_buffer(buffer, true, json, |msg| msg.as_bytes().into())
+}
+
+/// Like [`error_message_utf8()`], but without appending a trailing null.
+///
+/// This is useful when the caller treats the buffer as a counted string. The
+/// returned length (and the required length when the buffer is too small)
+/// won't include a null terminator either.
+///
+/// # Safety
+///
+/// `buf` must point to a writable buffer of at least `length` bytes.
+///
+/// [`error_message_utf8()`]: fn.error_message_utf8.html
+pub unsafe fn error_message_utf8_no_null(
+    buf: *mut c_char,
+    length: c_int,
+) -> c_int {
+    crate::null_pointer_check!(buf);
+    let buffer = match buffer_from_raw(buf as *mut u8, length) {
+        Some(buffer) => buffer,
+        None => return COPY_INVALID_LENGTH,
+    };
+
+    copy_error_into_buffer(buffer, false, error_message(), |msg| {
+        msg.as_bytes().into()
+    })
+}
+
+/// Peek at the most recent error and write its error message (`Display` impl)
+/// into the provided buffer as a UTF-16 encoded string.
+///
+/// This returns the number of `u16`s written (including the trailing null).
+/// If the buffer is too small, the negative of the required length is
+/// returned instead, so `-42` means you need a buffer of 42 `u16`s. Both are
+/// measured in the same unit as `length`. A negative `length` is rejected
+/// with [`COPY_INVALID_LENGTH`].
+///
+/// # Safety
+///
+/// `buf` must point to a writable buffer of at least `length` `u16`s.
+pub unsafe fn error_message_utf16(buf: *mut u16, length: c_int) -> c_int {
+    crate::null_pointer_check!(buf);
+    let buffer = match buffer_from_raw(buf, length) {
+        Some(buffer) => buffer,
+        None => return COPY_INVALID_LENGTH,
+    };
+
+    copy_error_into_buffer(buffer, true, error_message(), |msg| {
+        msg.encode_utf16().collect::<Vec<_>>().into()
+    })
+}
+
+/// Peek at the most recent error and write its error message (`Display` impl)
+/// into the provided buffer as a Latin-1 (ISO-8859-1) encoded string.
+///
+/// Characters which can't be represented in Latin-1 are replaced with `?`,
+/// and you can use [`error_message_latin1_lossy()`] to find out whether that
+/// happened.
+///
+/// This returns the number of bytes written (including the trailing null). If
+/// the buffer is too small, the negative of the required length is returned
+/// instead.
+///
+/// # Safety
+///
+/// `buf` must point to a writable buffer of at least `length` bytes.
+///
+/// [`error_message_latin1_lossy()`]: fn.error_message_latin1_lossy.html
+pub unsafe fn error_message_latin1(buf: *mut c_char, length: c_int) -> c_int {
+    LATIN1_LOSSY.with(|lossy| lossy.set(false));
+    crate::null_pointer_check!(buf);
+    let buffer = match buffer_from_raw(buf as *mut u8, length) {
+        Some(buffer) => buffer,
+        None => return COPY_INVALID_LENGTH,
+    };
+
+    let mut lossy = false;
+    let ret = copy_error_into_buffer(buffer, true, error_message(), |msg| {
+        msg.chars()
+            .map(|c| {
+                if (c as u32) < 0x100 {
+                    c as u8
+                } else {
+                    lossy = true;
+                    b'?'
+                }
+            })
+            .collect::<Vec<_>>()
+            .into()
+    });
+
+    LATIN1_LOSSY.with(|flag| flag.set(lossy));
+    ret
+}
+
+/// Did the most recent call to [`error_message_latin1()`] on this thread have
+/// to replace any characters with `?`?
+///
+/// This returns `1` if the message was lossy and `0` otherwise.
+///
+/// [`error_message_latin1()`]: fn.error_message_latin1.html
+pub extern "C" fn error_message_latin1_lossy() -> c_int {
+    if LATIN1_LOSSY.with(Cell::get) {
+        1
+    } else {
+        0
+    }
+}
+
+/// A callback used by [`error_message_custom()`] to convert a UTF-8 message
+/// into some other encoding.
+///
+/// It is called as `transcode(msg, msg_len, buf, buf_len)`, where `msg` points
+/// to `msg_len` bytes of UTF-8 (without a trailing null) and `buf` points to
+/// a writable buffer of `buf_len` bytes.
+///
+/// [`error_message_custom()`]: fn.error_message_custom.html
+pub type Transcoder = extern "C" fn(*const u8, usize, *mut u8, usize) -> c_int;
+
+/// Peek at the most recent error and write its error message into the
+/// provided buffer, using a caller-supplied [`Transcoder`] to convert it into
+/// the desired encoding.
+///
+/// This lets the host use encodings this crate doesn't know about (e.g.
+/// Shift-JIS or EBCDIC). Whatever the transcoder returns is passed straight
+/// back to the caller, so it is up to the transcoder to decide how to report
+/// errors and whether to append a trailing null. If there is no error, `0` is
+/// returned without calling the transcoder.
+///
+/// # Safety
+///
+/// `buf` must point to a writable buffer of at least `length` bytes.
+///
+/// [`Transcoder`]: type.Transcoder.html
+pub unsafe fn error_message_custom(
+    buf: *mut u8,
+    length: c_int,
+    transcode: Transcoder,
+) -> c_int {
+    crate::null_pointer_check!(buf);
+    if length < 0 {
+        return COPY_INVALID_LENGTH;
+    }
+
+    match error_message() {
+        Some(msg) => transcode(msg.as_ptr(), msg.len(), buf, length as usize),
+        None => COPY_NO_ERROR,
+    }
+}
+
+/// Turn a buffer passed in by the caller into a slice, returning `None` if
+/// the length is negative.
+///
+/// A negative length is almost certainly a bug on the caller's side (e.g. an
+/// overflow) and casting it to a `usize` would create an enormous slice. We
+/// can't update `LAST_ERROR` here because that's usually what the caller is
+/// trying to read.
+#[doc(hidden)]
+pub unsafe fn buffer_from_raw<'a, B>(
+    buf: *mut B,
+    length: c_int,
+) -> Option<&'a mut [B]> {
+    if length < 0 {
+        return None;
+    }
+
+    Some(slice::from_raw_parts_mut(buf, length as usize))
+}
+
+pub(crate) fn copy_error_into_buffer<B, F>(
+    buffer: &mut [B],
+    trailing_null: bool,
+    message: Option<String>,
+    error_msg: F,
+) -> c_int
+where
+    F: FnOnce(&str) -> Cow<'_, [B]>,
+    B: Copy + Nullable,
+{
+    match message {
+        Some(msg) => {
+            copy_str_into_buffer(&msg, buffer, trailing_null, error_msg)
+        },
+        None => COPY_NO_ERROR,
+    }
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! export_c_symbol {
+    (fn $name:ident($( $arg:ident : $type:ty ),*) -> $ret:ty) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $name($( $arg : $type),*) -> $ret {
+            $crate::error_handling::$name($( $arg ),*)
+        }
+    };
+    (fn $name:ident($( $arg:ident : $type:ty ),*)) => {
+        export_c_symbol!(fn $name($( $arg : $type),*) -> ());
+    }
+}
+
+/// As a workaround for [rust-lang/rfcs#2771][2771], you can use this macro to
+/// make sure the symbols for `ffi_helpers`'s error handling are correctly
+/// exported in your `cdylib`.
+///
+/// [2771]: https://github.com/rust-lang/rfcs/issues/2771
+#[macro_export]
+macro_rules! export_error_handling_functions {
+    () => {
+        #[allow(missing_docs)]
+        #[doc(hidden)]
+        pub mod __ffi_helpers_errors {
+            export_c_symbol!(fn ffi_helpers_abi_version() -> u32);
+            export_c_symbol!(fn ffi_helpers_null_int() -> ::libc::c_int);
+            export_c_symbol!(fn ffi_helpers_default_error_code() -> ::libc::c_int);
+            export_c_symbol!(fn ffi_helpers_copy_no_error() -> ::libc::c_int);
+            export_c_symbol!(fn ffi_helpers_copy_buffer_too_small() -> ::libc::c_int);
+            export_c_symbol!(fn ffi_helpers_copy_invalid_length() -> ::libc::c_int);
+            export_c_symbol!(fn clear_last_error());
+            export_c_symbol!(fn last_error_is_panic() -> ::libc::c_int);
+            export_c_symbol!(fn last_error_code() -> ::libc::c_int);
+            export_c_symbol!(fn last_error_code64() -> i64);
+            export_c_symbol!(fn last_error_length() -> ::libc::c_int);
+            export_c_symbol!(fn last_error_length_utf16() -> ::libc::c_int);
+            export_c_symbol!(fn last_error_length_encoding(encoding: ::libc::c_int) -> ::libc::c_int);
+            export_c_symbol!(fn error_message_utf8(buf: *mut ::libc::c_char, length: ::libc::c_int) -> ::libc::c_int);
+            export_c_symbol!(fn error_message_with_code_utf8(buf: *mut ::libc::c_char, length: ::libc::c_int) -> ::libc::c_int);
+            export_c_symbol!(fn error_message_for_utf8(key: u64, buf: *mut ::libc::c_char, length: ::libc::c_int) -> ::libc::c_int);
+            export_c_symbol!(fn error_message_utf8_no_null(buf: *mut ::libc::c_char, length: ::libc::c_int) -> ::libc::c_int);
+            export_c_symbol!(fn error_message_utf16(buf: *mut u16, length: ::libc::c_int) -> ::libc::c_int);
+            export_c_symbol!(fn error_message_custom(buf: *mut u8, length: ::libc::c_int, transcode: $crate::error_handling::Transcoder) -> ::libc::c_int);
+            export_c_symbol!(fn error_message_latin1(buf: *mut ::libc::c_char, length: ::libc::c_int) -> ::libc::c_int);
+            export_c_symbol!(fn error_message_latin1_lossy() -> ::libc::c_int);
+            export_c_symbol!(fn error_context_count() -> ::libc::c_int);
+            export_c_symbol!(fn set_last_error_message_utf16(msg: *const u16, length: ::libc::c_int) -> ::libc::c_int);
+            export_c_symbol!(fn error_key_count() -> ::libc::c_int);
+            export_c_symbol!(fn error_key_at(index: ::libc::c_int, key: *mut u64) -> ::libc::c_int);
+            export_c_symbol!(fn error_context_pair_utf8(index: ::libc::c_int, key_buf: *mut ::libc::c_char, key_length: ::libc::c_int, value_buf: *mut ::libc::c_char, value_length: ::libc::c_int) -> ::libc::c_int);
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str;
+
+    fn clear_last_error() { let _ = take_slot(DEFAULT_SLOT); }
+
+    export_error_handling_functions!();
+
+    #[test]
+    fn the_abi_version_is_exported() {
+        let got = unsafe { __ffi_helpers_errors::ffi_helpers_abi_version() };
+
+        assert_eq!(got, ABI_VERSION);
+    }
+
+    #[test]
+    fn include_the_code_in_the_message() {
+        clear_last_error();
+        update_last_error_with_code(anyhow::anyhow!("File not found"), 42);
+        let mut buffer = [0 as c_char; 64];
+
+        let ret =
+            unsafe { error_message_with_code_utf8(buffer.as_mut_ptr(), 64) };
+
+        let expected = "[E0042] File not found";
+        assert_eq!(ret as usize, expected.len() + 1);
+        let got: Vec<u8> =
+            buffer[..expected.len()].iter().map(|&c| c as u8).collect();
+        assert_eq!(got, expected.as_bytes());
+        clear_last_error();
+    }
+
+    #[test]
+    fn set_the_message_from_utf16() {
+        clear_last_error();
+        let msg: Vec<u16> = "Ünïcödé error".encode_utf16().collect();
+        let invalid = [0xd800_u16, 0x0041];
+
+        let got = unsafe {
+            set_last_error_message_utf16(msg.as_ptr(), msg.len() as c_int)
+        };
+
+        assert_eq!(got, 0);
+        let mut buffer = [0 as c_char; 32];
+        let written = unsafe { error_message_utf8(buffer.as_mut_ptr(), 32) };
+        let utf8: Vec<u8> = buffer[..written as usize - 1]
+            .iter()
+            .map(|&c| c as u8)
+            .collect();
+        assert_eq!(str::from_utf8(&utf8).unwrap(), "Ünïcödé error");
+
+        let got = unsafe { set_last_error_message_utf16(invalid.as_ptr(), 2) };
+        assert_eq!(got, -1);
+        assert_eq!(error_message().unwrap(), "Ünïcödé error");
+        clear_last_error();
+    }
+
+    #[test]
+    fn list_the_keys_holding_errors() {
+        clear_last_error();
+        for key in [7, 3, 5] {
+            update_last_error_for(key, anyhow::anyhow!("Error #{}", key));
+        }
+
+        assert_eq!(error_keys(), vec![3, 5, 7]);
+        assert_eq!(error_key_count(), 3);
+        let mut keys = vec![0; 3];
+        for (i, key) in keys.iter_mut().enumerate() {
+            assert_eq!(unsafe { error_key_at(i as c_int, key) }, 1);
+        }
+        assert_eq!(keys, vec![3, 5, 7]);
+        let mut key = 42;
+        assert_eq!(unsafe { error_key_at(3, &mut key) }, 0);
+        assert_eq!(key, 42, "The key is untouched when out of bounds");
+
+        for key in error_keys() {
+            let _ = take_last_error_for(key);
+        }
+        assert_eq!(error_key_count(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn read_every_error_message_as_json() {
+        clear_last_error();
+        update_last_error(anyhow::anyhow!("File not found"));
+        update_last_error_for(3, anyhow::anyhow!("Timed out"));
+        let mut buffer = vec![0 as c_char; 128];
+
+        let bytes_written = unsafe {
+            all_error_messages_json(buffer.as_mut_ptr(), buffer.len() as _)
+        };
+
+        assert!(bytes_written > 0);
+        let json: Vec<u8> = buffer[..bytes_written as usize - 1]
+            .iter()
+            .map(|&c| c as u8)
+            .collect();
+        let got: HashMap<String, String> =
+            serde_json::from_slice(&json).unwrap();
+        assert_eq!(got.len(), 2);
+        assert_eq!(got["0"], "File not found");
+        assert_eq!(got["3"], "Timed out");
+
+        let _ = take_last_error_for(3);
+        clear_last_error();
+        let empty = unsafe {
+            all_error_messages_json(buffer.as_mut_ptr(), buffer.len() as _)
+        };
+        assert_eq!(empty, COPY_NO_ERROR);
+    }
+
+    #[test]
+    fn errors_survive_a_thread_hop_inside_a_scope() {
+        clear_last_error();
+        update_last_error_with_code(anyhow::anyhow!("Lost in transit"), 42);
+
+        let scope = enter_error_scope();
+        assert!(error_message().is_none());
+
+        let got = std::thread::spawn(move || {
+            exit_error_scope(scope);
+            (error_message(), last_error_code())
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(got, (Some(String::from("Lost in transit")), 42));
+    }
+
+    #[test]
+    fn attach_context_to_the_last_error() {
+        clear_last_error();
+        update_last_error(anyhow::anyhow!("Request failed"));
+        add_error_context("request_id", "1234");
+        add_error_context("user", "ferris");
+        let mut key = [0 as c_char; 16];
+        let mut value = [0 as c_char; 16];
+        let mut tiny = [0 as c_char; 4];
+
+        assert_eq!(error_context_count(), 2);
+        let (key_ptr, value_ptr) = (key.as_mut_ptr(), value.as_mut_ptr());
+        let (first, missing, too_small) = unsafe {
+            (
+                error_context_pair_utf8(0, key_ptr, 16, value_ptr, 16),
+                error_context_pair_utf8(2, key_ptr, 16, value_ptr, 16),
+                error_context_pair_utf8(1, tiny.as_mut_ptr(), 4, value_ptr, 16),
+            )
+        };
+
+        assert_eq!(first, 1);
+        let as_bytes = |buf: &[c_char]| -> Vec<u8> {
+            buf.iter()
+                .map(|&c| c as u8)
+                .take_while(|&c| c != 0)
+                .collect()
+        };
+        assert_eq!(as_bytes(&key), b"request_id");
+        assert_eq!(as_bytes(&value), b"1234");
+        assert_eq!(missing, 0);
+        assert_eq!(too_small, -7);
+
+        // setting a new error throws away the old context
+        update_last_error(anyhow::anyhow!("Another error"));
+        assert_eq!(error_context_count(), 0);
+        clear_last_error();
+    }
+
+    #[test]
+    fn copy_the_message_into_a_fixed_size_array() {
+        clear_last_error();
+        update_last_error(anyhow::anyhow!("Short"));
+        let mut big = [0 as c_char; 256];
+        let mut small = [0 as c_char; 4];
+
+        let (fits, too_small) = unsafe {
+            (
+                error_message_fixed(&mut big),
+                error_message_fixed(&mut small),
+            )
+        };
+
+        assert_eq!(fits, 6);
+        let got: Vec<u8> = big[..6].iter().map(|&c| c as u8).collect();
+        assert_eq!(got, b"Short\0");
+        assert_eq!(too_small, -6);
+        assert_eq!(small, [0; 4]);
+        clear_last_error();
+    }
+
+    #[test]
+    fn copy_results_have_named_constants() {
+        assert_eq!(COPY_NO_ERROR, 0);
+        assert_eq!(COPY_BUFFER_TOO_SMALL, -1);
+        assert_eq!(COPY_INVALID_LENGTH, c_int::MIN);
+
+        clear_last_error();
+        let mut buffer = [0 as c_char; 16];
+        let (no_error, invalid) = unsafe {
+            (
+                error_message_utf8(buffer.as_mut_ptr(), 16),
+                error_message_utf8(buffer.as_mut_ptr(), -16),
+            )
+        };
+
+        assert_eq!(no_error, COPY_NO_ERROR);
+        assert_eq!(invalid, COPY_INVALID_LENGTH);
+
+        // an empty message still needs room for the trailing null
+        update_last_error(anyhow::anyhow!(""));
+        let too_small = unsafe { error_message_utf8(buffer.as_mut_ptr(), 0) };
+
+        assert_eq!(too_small, COPY_BUFFER_TOO_SMALL);
+        assert_ne!(too_small, COPY_INVALID_LENGTH);
+        clear_last_error();
+    }
+
+    #[test]
+    fn negative_buffer_lengths_are_rejected() {
+        clear_last_error();
+        update_last_error(anyhow::anyhow!("An error"));
+        let mut utf8 = [0 as c_char; 4];
+        let mut utf16 = [0_u16; 4];
+
+        unsafe {
+            let utf8_ret = error_message_utf8(utf8.as_mut_ptr(), -1);
+            let utf16_ret = error_message_utf16(utf16.as_mut_ptr(), -1);
+            let latin1_ret = error_message_latin1(utf8.as_mut_ptr(), -1);
+
+            assert_eq!(utf8_ret, COPY_INVALID_LENGTH);
+            assert_eq!(utf16_ret, COPY_INVALID_LENGTH);
+            assert_eq!(latin1_ret, COPY_INVALID_LENGTH);
+        }
+
+        assert_eq!(utf8, [0; 4]);
+        assert_eq!(utf16, [0; 4]);
+        assert!(error_message().is_some());
+        clear_last_error();
+    }
+
+    #[test]
+    fn the_sentinel_values_are_exported() {
+        use self::__ffi_helpers_errors::*;
+
+        unsafe {
+            assert_eq!(ffi_helpers_null_int(), 0);
+            assert_eq!(ffi_helpers_default_error_code(), -1);
+            assert_eq!(ffi_helpers_copy_no_error(), COPY_NO_ERROR);
+            assert_eq!(
+                ffi_helpers_copy_buffer_too_small(),
+                COPY_BUFFER_TOO_SMALL
+            );
+            assert_eq!(ffi_helpers_copy_invalid_length(), COPY_INVALID_LENGTH);
+        }
+    }
+
+    #[test]
+    fn update_the_error() {
+        clear_last_error();
+
+        let err_msg = "An Error Occurred";
+        let e = anyhow::anyhow!(err_msg);
+
+        update_last_error(e);
+
+        let got_err_msg =
+            format!("{:#}", take_slot(DEFAULT_SLOT).unwrap().error);
+        assert_eq!(got_err_msg, err_msg);
+    }
+
+    #[test]
+    fn take_the_last_error() {
+        clear_last_error();
+
+        let err_msg = "An Error Occurred";
+        let e = anyhow::anyhow!(err_msg);
+        update_last_error(e);
+
+        let got_err_msg = format!("{:#}", take_last_error().unwrap());
+        assert_eq!(got_err_msg, err_msg);
+    }
+
+    #[test]
+    fn take_the_code_and_message_together() {
+        clear_last_error();
+        assert_eq!(last_error_code(), 0);
+
+        update_last_error_with_code(anyhow::anyhow!("Not found"), 404);
+        assert_eq!(last_error_code(), 404);
+
+        let got = take_last_error_parts().unwrap();
+        assert_eq!(got, (404, String::from("Not found")));
+        assert!(take_last_error_parts().is_none());
+
+        update_last_error(anyhow::anyhow!("No code"));
+        assert_eq!(last_error_code(), DEFAULT_ERROR_CODE);
+        clear_last_error();
+    }
+
+    #[test]
+    fn wide_error_codes_are_preserved() {
+        clear_last_error();
+        let code = (0x7ead_beef_i64 << 32) | 0x1234;
+
+        update_last_error_with_code64(anyhow::anyhow!("Wide"), code);
+        assert_eq!(last_error_code64(), code);
+        assert_eq!(last_error_code(), 0x1234);
+
+        update_last_error_with_code(anyhow::anyhow!("Narrow"), -5);
+        assert_eq!(last_error_code64(), -5);
+        clear_last_error();
+    }
+
+    #[test]
+    fn copy_the_message_using_a_custom_transcoder() {
+        extern "C" fn identity(
+            msg: *const u8,
+            msg_len: usize,
+            buf: *mut u8,
+            buf_len: usize,
+        ) -> c_int {
+            if msg_len > buf_len {
+                return -1;
+            }
+            unsafe { std::ptr::copy_nonoverlapping(msg, buf, msg_len) };
+            msg_len as c_int
+        }
+
+        clear_last_error();
+        let mut buffer = [0_u8; 32];
+        let ret =
+            unsafe { error_message_custom(buffer.as_mut_ptr(), 32, identity) };
+        assert_eq!(ret, 0);
+
+        update_last_error(anyhow::anyhow!("Custom"));
+        let ret =
+            unsafe { error_message_custom(buffer.as_mut_ptr(), 32, identity) };
+        assert_eq!(&buffer[..ret as usize], b"Custom");
+
+        let ret =
+            unsafe { error_message_custom(buffer.as_mut_ptr(), 2, identity) };
+        assert_eq!(ret, -1);
+        clear_last_error();
+    }
+
+    #[test]
+    fn reentrant_calls_dont_panic() {
+        #[derive(Debug)]
+        struct Reentrant;
+
+        impl Display for Reentrant {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                // LAST_ERROR is borrowed while the message is being rendered
+                update_last_error(anyhow::anyhow!("Nested"));
+                assert!(take_last_error().is_none());
+                write!(f, "Reentrant")
+            }
+        }
+
+        impl std::error::Error for Reentrant {}
+
+        clear_last_error();
+        update_last_error(Reentrant);
+
+        assert_eq!(error_message().unwrap(), "Reentrant");
+        assert!(last_error_is::<Reentrant>());
+        clear_last_error();
+    }
+
+    #[test]
+    fn read_the_error_while_it_is_being_rendered() {
+        #[derive(Debug, Default)]
+        struct ReadsItself {
+            nested: AtomicBool,
+        }
+
+        impl Display for ReadsItself {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                if self.nested.swap(true, Ordering::SeqCst) {
+                    return write!(f, "inner");
+                }
+
+                // LAST_ERROR is borrowed while the message is being rendered
+                let message = error_message().unwrap_or_default();
+                let mut buffer = [0 as c_char; 16];
+                let copied =
+                    unsafe { error_message_utf8(buffer.as_mut_ptr(), 16) };
+                let length = last_error_length();
+                self.nested.store(false, Ordering::SeqCst);
+
+                write!(f, "outer({}, {}, {})", message, copied, length)
+            }
+        }
+
+        impl std::error::Error for ReadsItself {}
+
+        clear_last_error();
+        update_last_error(ReadsItself::default());
+
+        assert_eq!(error_message().unwrap(), "outer(inner, 6, 6)");
+        clear_last_error();
+    }
+
+    #[test]
+    fn inspect_the_error_without_cloning_it() {
+        struct Counter(usize);
+
+        impl std::fmt::Write for Counter {
+            fn write_str(&mut self, s: &str) -> std::fmt::Result {
+                self.0 += s.len();
+                Ok(())
+            }
+        }
+
+        clear_last_error();
+        assert!(with_last_error(|e| e.is_none()));
+
+        update_last_error(anyhow::anyhow!("Borrowed"));
+        let length = with_last_error(|e| {
+            let mut counter = Counter(0);
+            std::fmt::write(&mut counter, format_args!("{}", e.unwrap()))
+                .unwrap();
+            counter.0
+        });
+
+        assert_eq!(length, "Borrowed".len());
+        clear_last_error();
+    }
+
+    #[test]
+    fn set_a_plain_string_error() {
+        clear_last_error();
+
+        set_last_error_str("Just a message");
+
+        assert_eq!(error_message().unwrap(), "Just a message");
+        assert_eq!(last_error_code(), DEFAULT_ERROR_CODE);
+        let got = take_last_error_as::<StringError>().unwrap();
+        assert_eq!(got.message, "Just a message");
+    }
+
+    #[test]
+    fn keyed_errors_are_independent() {
+        clear_last_error();
+
+        update_last_error_for(1, anyhow::anyhow!("First"));
+        update_last_error_for(2, anyhow::anyhow!("Second"));
+        assert!(error_message().is_none());
+
+        let mut buffer = [0_u8; 32];
+        let ret = unsafe {
+            error_message_for_utf8(2, buffer.as_mut_ptr() as *mut c_char, 32)
+        };
+        assert_eq!(&buffer[..ret as usize - 1], b"Second");
+
+        assert_eq!(take_last_error_for(1).unwrap().to_string(), "First");
+        assert!(take_last_error_for(1).is_none());
+        assert_eq!(take_last_error_for(2).unwrap().to_string(), "Second");
+    }
+
+    #[test]
+    fn take_the_last_error_as_a_concrete_type() {
+        clear_last_error();
+        update_last_error(crate::NullPointer);
+
+        assert!(last_error_is::<crate::NullPointer>());
+        assert!(!last_error_is::<crate::panic::Panic>());
+        assert_eq!(
+            take_last_error_as::<crate::NullPointer>().unwrap(),
+            crate::NullPointer
+        );
+
+        update_last_error(anyhow::anyhow!("Not a null pointer"));
+        let original = take_last_error_as::<crate::NullPointer>()
+            .unwrap_err()
+            .unwrap();
+        assert_eq!(original.to_string(), "Not a null pointer");
+
+        assert!(take_last_error_as::<crate::NullPointer>()
+            .unwrap_err()
+            .is_none());
+    }
+
+    #[test]
+    fn check_whether_the_last_error_was_a_panic() {
+        clear_last_error();
+
+        update_last_error(Panic {
+            message: String::from("Oops"),
+        });
+        assert_eq!(last_error_is_panic(), 1);
+
+        update_last_error(anyhow::anyhow!("A normal error"));
+        assert_eq!(last_error_is_panic(), 0);
+    }
+
+    #[test]
+    fn the_error_guard_clears_errors_when_dropped() {
+        clear_last_error();
+
+        let guard = error_guard();
+        update_last_error(anyhow::anyhow!("First"));
+        assert_eq!(guard.take().unwrap().to_string(), "First");
+
+        update_last_error(anyhow::anyhow!("Second"));
+        drop(guard);
+
+        assert!(error_message().is_none());
+    }
+
+    #[test]
+    fn get_the_last_error_messages_length() {
+        clear_last_error();
+
+        let err_msg = "Some Context: An Error Occurred";
+        let should_be = err_msg.len() + 1;
+
+        let e = anyhow::anyhow!("An Error Occurred").context("Some Context");
+        update_last_error(e);
+
+        // Get a valid error message's length
+        let got = last_error_length();
+        assert_eq!(got, should_be as c_int);
+
+        // Then clear the error message and make sure we get 0
+        clear_last_error();
+        let got = last_error_length();
+        assert_eq!(got, 0);
+    }
+
+    #[test]
+    fn get_the_last_error_messages_length_in_any_encoding() {
+        clear_last_error();
+
+        // "é" is 2 bytes in UTF-8 and "𝄞" needs a surrogate pair in UTF-16
+        update_last_error(anyhow::anyhow!("é𝄞"));
+
+        assert_eq!(last_error_length_encoding(ENCODING_UTF8), 2 + 4 + 1);
+        assert_eq!(last_error_length_encoding(ENCODING_UTF16), 1 + 2 + 1);
+        assert_eq!(last_error_length_encoding(ENCODING_UTF32), 1 + 1 + 1);
+        assert_eq!(last_error_length_encoding(42), -1);
+
+        assert_eq!(last_error_length(), 7);
+        assert_eq!(last_error_length_utf16(), 4);
+    }
+
+    #[test]
+    fn write_the_last_error_message_into_a_buffer() {
+        clear_last_error();
+
+        let expected = "Some Context: An Error Occurred";
+
+        let e = anyhow::anyhow!("An Error Occurred").context("Some Context");
+        update_last_error(e);
+
+        let mut buffer: Vec<u8> = vec![0; 40];
+        let bytes_written = unsafe {
+            error_message_utf8(
+                buffer.as_mut_ptr() as *mut c_char,
+                buffer.len() as _,
+            )
+        };
+
+        assert!(bytes_written > 0);
+        assert_eq!(bytes_written as usize, expected.len() + 1);
+
+        let msg =
+            str::from_utf8(&buffer[..bytes_written as usize - 1]).unwrap();
+        assert_eq!(msg, expected);
+    }
+
+    #[test]
+    fn undersized_buffers_report_the_required_length() {
+        clear_last_error();
+        update_last_error(anyhow::anyhow!("An Error Occurred"));
+
+        let mut buffer: Vec<u8> = vec![0; 4];
+        let got = unsafe {
+            error_message_utf8(
+                buffer.as_mut_ptr() as *mut c_char,
+                buffer.len() as _,
+            )
+        };
+        assert_eq!(got, -last_error_length());
+
+        let mut buffer: Vec<u16> = vec![0; 4];
+        let got = unsafe {
+            error_message_utf16(buffer.as_mut_ptr(), buffer.len() as _)
+        };
+        assert_eq!(got, -last_error_length_utf16());
+
+        // the required length can be passed straight back in
+        buffer.resize(-got as usize, 0);
+        let written = unsafe { error_message_utf16(buffer.as_mut_ptr(), -got) };
+        assert_eq!(written, -got);
+    }
+
+    #[test]
+    fn write_the_last_error_message_without_a_trailing_null() {
+        clear_last_error();
+        update_last_error(anyhow::anyhow!("Oops"));
+
+        let mut buffer: Vec<u8> = vec![0xff; 4];
+        let bytes_written = unsafe {
+            error_message_utf8_no_null(
+                buffer.as_mut_ptr() as *mut c_char,
+                buffer.len() as _,
+            )
+        };
+
+        assert_eq!(bytes_written, 4);
+        assert_eq!(buffer, b"Oops");
+
+        let mut buffer: Vec<u8> = vec![0xff; 8];
+        let bytes_written = unsafe {
+            error_message_utf8_no_null(
+                buffer.as_mut_ptr() as *mut c_char,
+                buffer.len() as _,
+            )
+        };
+
+        assert_eq!(bytes_written, 4);
+        assert_eq!(buffer, b"Oops\xff\xff\xff\xff");
+    }
+
+    #[test]
+    fn write_the_last_error_message_as_latin1() {
+        clear_last_error();
+        update_last_error(anyhow::anyhow!("Café ☕"));
+
+        let mut buffer: Vec<u8> = vec![0xff; 16];
+        let bytes_written = unsafe {
+            error_message_latin1(
+                buffer.as_mut_ptr() as *mut c_char,
+                buffer.len() as _,
+            )
+        };
+
+        assert_eq!(bytes_written, 7);
+        assert_eq!(&buffer[..7], b"Caf\xe9 ?\0");
+        assert_eq!(error_message_latin1_lossy(), 1);
+
+        update_last_error(anyhow::anyhow!("Café"));
+        unsafe {
+            error_message_latin1(
+                buffer.as_mut_ptr() as *mut c_char,
+                buffer.len() as _,
+            );
+        }
+        assert_eq!(error_message_latin1_lossy(), 0);
+        clear_last_error();
+    }
+
+    #[test]
+    fn error_transform_can_redact_messages() {
+        let _guard = crate::lock_global_config();
+        clear_last_error();
+        set_error_transform(Box::new(|e| {
+            let msg = format!("{:#}", e);
+            if msg.contains("hunter2") {
+                Some(anyhow::anyhow!(msg.replace("hunter2", "*******")))
+            } else {
+                Some(e)
+            }
+        }));
+
+        update_last_error(anyhow::anyhow!("Invalid password: hunter2"));
+        clear_error_transform();
+
+        assert_eq!(error_message().unwrap(), "Invalid password: *******");
+    }
+
+    #[test]
+    fn error_transform_can_suppress_errors() {
+        let _guard = crate::lock_global_config();
+        clear_last_error();
+        set_error_transform(Box::new(|e| {
+            if format!("{}", e).contains("ignore me") {
+                None
+            } else {
+                Some(e)
+            }
+        }));
+
+        update_last_error(anyhow::anyhow!("Previous error"));
+        update_last_error(anyhow::anyhow!("Please ignore me"));
+        clear_error_transform();
+
+        assert!(error_message().is_none());
+    }
+
+    #[test]
+    fn recover_from_a_poisoned_config_lock() {
+        let _guard = crate::lock_global_config();
+        clear_last_error();
+
+        let _ = std::panic::catch_unwind(|| {
+            let _transform = ERROR_TRANSFORM.write().unwrap();
+            panic!("Poisoning the lock");
+        });
+        assert!(ERROR_TRANSFORM.is_poisoned());
+
+        update_last_error(anyhow::anyhow!("Still works"));
+        assert_eq!(error_message().unwrap(), "Still works");
+
+        set_error_transform(Box::new(Some));
+        clear_error_transform();
+        ERROR_TRANSFORM.clear_poison();
     }
 }
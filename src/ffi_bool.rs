@@ -0,0 +1,71 @@
+use crate::Nullable;
+
+/// An ABI-stable boolean, guaranteed to be a single byte which is either `0`
+/// or `1`.
+///
+/// This is useful when the other side of the FFI boundary expects a `bool` or
+/// `uint8_t` rather than the `c_int` typically used for boolean-ish return
+/// values.
+///
+/// # Examples
+///
+/// ```rust
+/// use ffi_helpers::FfiBool;
+///
+/// #[no_mangle]
+/// pub extern "C" fn is_even(n: u32) -> FfiBool { (n % 2 == 0).into() }
+///
+/// assert_eq!(is_even(2), FfiBool::True);
+/// assert!(!bool::from(is_even(3)));
+/// ```
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum FfiBool {
+    /// `false`.
+    False = 0,
+    /// `true`.
+    True = 1,
+}
+
+impl From<bool> for FfiBool {
+    fn from(b: bool) -> FfiBool {
+        if b {
+            FfiBool::True
+        } else {
+            FfiBool::False
+        }
+    }
+}
+
+impl From<FfiBool> for bool {
+    fn from(b: FfiBool) -> bool { b == FfiBool::True }
+}
+
+impl Nullable for FfiBool {
+    const NULL: Self = FfiBool::False;
+
+    #[inline]
+    fn is_null(&self) -> bool { *self == Self::NULL }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_through_a_bool() {
+        for &b in &[true, false] {
+            assert_eq!(bool::from(FfiBool::from(b)), b);
+        }
+
+        assert_eq!(FfiBool::True as u8, 1);
+        assert_eq!(FfiBool::False as u8, 0);
+    }
+
+    #[test]
+    fn false_is_null() {
+        assert_eq!(FfiBool::NULL, FfiBool::False);
+        assert!(FfiBool::False.is_null());
+        assert!(!FfiBool::True.is_null());
+    }
+}
@@ -5,14 +5,55 @@ mod nullable;
 #[macro_use]
 pub mod task;
 
+pub mod collections;
 pub mod error_handling;
+mod ffi_bool;
+pub mod outcome;
 pub mod panic;
 mod split;
+#[macro_use]
+pub mod strings;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+
+#[doc(hidden)]
+pub use crate::nullable::{NullCheck, OtherArgument, PointerArgument};
+#[cfg(feature = "macros")]
+pub use ffi_helpers_macros::{catch_panics, Nullable};
 
 pub use crate::{
     error_handling::{error_message, take_last_error, update_last_error},
-    nullable::{NullPointer, Nullable},
+    ffi_bool::FfiBool,
+    nullable::{
+        into_nullable_ptr, is_aligned, Misaligned, NullPointer, Nullable,
+    },
+    outcome::Outcome,
     panic::catch_panic,
-    split::{split_closure, Split},
+    split::{
+        split_closure, split_closure_scoped, AllocError, RegisteredCallback,
+        ScopedCallback, Split,
+    },
     task::Task,
 };
+
+/// Get a human-readable summary of every global setting, one `name: value`
+/// per line.
+///
+/// This is intended for diagnostics, e.g. asking a user to send you the
+/// output when something misbehaves. Hooks and transforms are only reported
+/// as being installed or not.
+pub fn debug_dump_config() -> String {
+    let mut out = String::new();
+    error_handling::dump_config(&mut out);
+    panic::dump_config(&mut out);
+    task::dump_config(&mut out);
+    out
+}
+
+/// Serializes tests which touch the crate's global configuration.
+#[cfg(test)]
+pub(crate) fn lock_global_config() -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    LOCK.lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
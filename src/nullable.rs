@@ -3,8 +3,14 @@ use thiserror::Error;
 /// An object which has an "obviously invalid" value, for use with the
 /// [`null_pointer_check!()`][npc] macro.
 ///
-/// This trait is implemented for all integer types and raw pointers, returning
-/// `0` and `null` respectively.
+/// This trait is implemented for all integer types, `char` and raw pointers,
+/// returning `0`, `'\0'` and `null` respectively. Opaque `void*` handles
+/// (i.e. `*const c_void` and `*mut c_void`) are just raw pointers, so they
+/// work too.
+///
+/// With the `macros` feature you can `#[derive(Nullable)]` on a struct whose
+/// fields are all `Nullable` (e.g. a `#[repr(C)]` struct returned by value),
+/// in which case `NULL` has every field set to `NULL`.
 ///
 /// [npc]: macro.null_pointer_check.html
 pub trait Nullable {
@@ -50,6 +56,13 @@ impl<T> Nullable for Option<T> {
     fn is_null(&self) -> bool { self.is_none() }
 }
 
+impl Nullable for char {
+    const NULL: Self = '\0';
+
+    #[inline]
+    fn is_null(&self) -> bool { *self == Self::NULL }
+}
+
 impl Nullable for () {
     const NULL: Self = ();
 
@@ -141,11 +154,131 @@ macro_rules! null_pointer_check {
     }};
 }
 
+/// Like [`null_pointer_check!()`], but also makes sure the pointer is
+/// correctly aligned for the type it points to.
+///
+/// Dereferencing a misaligned pointer is just as bad as dereferencing a null
+/// one. If the pointer is misaligned the `LAST_ERROR` is updated with
+/// [`Misaligned`] and we return early.
+///
+/// # Examples
+///
+/// ```rust
+/// use ffi_helpers::error_handling;
+///
+/// #[no_mangle]
+/// unsafe extern "C" fn read_u32(value: *const u32) -> u32 {
+///     ffi_helpers::aligned_pointer_check!(value);
+///
+///     *value
+/// }
+///
+/// # unsafe {
+/// let misaligned = 0x1001 as *const u32;
+/// assert_eq!(read_u32(misaligned), 0);
+/// assert!(error_handling::last_error_is::<ffi_helpers::Misaligned>());
+/// # }
+/// ```
+///
+/// [`null_pointer_check!()`]: macro.null_pointer_check.html
+/// [`Misaligned`]: struct.Misaligned.html
+#[macro_export]
+macro_rules! aligned_pointer_check {
+    ($ptr:expr) => {
+        $crate::aligned_pointer_check!($ptr, Nullable::NULL)
+    };
+    ($ptr:expr, $null:expr) => {{
+        #[allow(unused_imports)]
+        use $crate::Nullable;
+        // only evaluate the expression once
+        let ptr = $ptr;
+        $crate::null_pointer_check!(ptr, $null);
+        if !$crate::is_aligned(ptr) {
+            $crate::error_handling::update_last_error($crate::Misaligned);
+            return $null;
+        }
+    }};
+}
+
+/// Is this pointer correctly aligned for the type it points to?
+pub fn is_aligned<T>(ptr: *const T) -> bool { ptr.is_aligned() }
+
+/// Convert a `Result` into a pointer, returning `null` and updating
+/// `LAST_ERROR` if it was an `Err`.
+///
+/// This bridges internal helpers written using idiomatic `Result`s with the
+/// "return `null` on failure" convention used across the FFI boundary.
+///
+/// # Examples
+///
+/// ```rust
+/// use ffi_helpers::into_nullable_ptr;
+/// # use anyhow::Error;
+///
+/// fn parse(number: &str) -> Result<*mut u32, Error> {
+///     let number = number.parse()?;
+///     Ok(Box::into_raw(Box::new(number)))
+/// }
+///
+/// let ptr = into_nullable_ptr(parse("not a number"));
+///
+/// assert!(ptr.is_null());
+/// assert!(ffi_helpers::error_message().is_some());
+/// ```
+pub fn into_nullable_ptr<T, E>(result: Result<*mut T, E>) -> *mut T
+where
+    E: Into<anyhow::Error>,
+{
+    match result {
+        Ok(ptr) => ptr,
+        Err(e) => {
+            crate::error_handling::update_last_error(e);
+            std::ptr::null_mut()
+        },
+    }
+}
+
+/// Wraps an argument to a function generated by [`export_fallible!()`] so
+/// it can be null-checked if (and only if) it is a raw pointer.
+///
+/// Calling `(&NullCheck(&arg)).is_null_pointer()` picks the
+/// [`PointerArgument`] impl for `*const T` and `*mut T`, and falls back to
+/// [`OtherArgument`] (which is never null) for everything else.
+///
+/// [`export_fallible!()`]: macro.export_fallible.html
+#[doc(hidden)]
+pub struct NullCheck<'a, T>(pub &'a T);
+
+#[doc(hidden)]
+pub trait PointerArgument {
+    fn is_null_pointer(&self) -> bool;
+}
+
+impl<T: ?Sized> PointerArgument for NullCheck<'_, *const T> {
+    fn is_null_pointer(&self) -> bool { self.0.is_null() }
+}
+
+impl<T: ?Sized> PointerArgument for NullCheck<'_, *mut T> {
+    fn is_null_pointer(&self) -> bool { self.0.is_null() }
+}
+
+#[doc(hidden)]
+pub trait OtherArgument {
+    fn is_null_pointer(&self) -> bool { false }
+}
+
+impl<T> OtherArgument for &NullCheck<'_, T> {}
+
 /// A `null` pointer was encountered where it wasn't expected.
 #[derive(Debug, Copy, Clone, PartialEq, Error)]
 #[error("A null pointer was passed in where it wasn't expected")]
 pub struct NullPointer;
 
+/// A pointer wasn't correctly aligned for the type it points to.
+#[derive(Debug, Copy, Clone, PartialEq, Error)]
+#[error("A misaligned pointer was passed in")]
+pub struct Misaligned;
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -158,7 +291,7 @@ mod tests {
 
     #[test]
     fn can_detect_null_pointers() {
-        let null = 0 as *const u8;
+        let null = std::ptr::null::<u8>();
         assert!(<_ as Nullable>::is_null(&null));
     }
 
@@ -168,4 +301,90 @@ mod tests {
         let not_null = &thing as *const i32;
         assert!(!<_ as Nullable>::is_null(&not_null));
     }
+
+    #[test]
+    fn void_pointers_are_nullable() {
+        use std::ffi::c_void;
+
+        unsafe fn read(handle: *mut c_void) -> u32 {
+            null_pointer_check!(handle);
+            *(handle as *mut u32)
+        }
+
+        let _ = crate::take_last_error();
+        let mut value = 42_u32;
+
+        let got = unsafe { read(std::ptr::null_mut()) };
+        assert_eq!(got, 0);
+        assert!(crate::error_handling::last_error_is::<NullPointer>());
+        let _ = crate::take_last_error();
+
+        let got = unsafe { read(&mut value as *mut u32 as *mut c_void) };
+        assert_eq!(got, 42);
+        assert!(<*const c_void as Nullable>::NULL.is_null());
+    }
+
+    #[test]
+    fn misaligned_pointers_return_early() {
+        unsafe fn read(ptr: *mut u32) -> u32 {
+            aligned_pointer_check!(ptr);
+            *ptr
+        }
+
+        let _ = crate::take_last_error();
+        let mut value = 42_u32;
+
+        let misaligned = (&mut value as *mut u32 as usize + 1) as *mut u32;
+
+        let got = unsafe { read(misaligned) };
+        assert_eq!(got, 0);
+        assert!(crate::error_handling::last_error_is::<Misaligned>());
+
+        let got = unsafe { read(&mut value) };
+        assert_eq!(got, 42);
+    }
+
+    #[test]
+    fn the_aligned_pointer_is_only_evaluated_once() {
+        unsafe fn read(ptr: *mut u32, evaluated: &mut u32) -> u32 {
+            aligned_pointer_check!({
+                *evaluated += 1;
+                ptr
+            });
+            *ptr
+        }
+
+        let mut value = 42_u32;
+        let mut evaluated = 0;
+
+        let got = unsafe { read(&mut value, &mut evaluated) };
+
+        assert_eq!(got, 42);
+        assert_eq!(evaluated, 1);
+    }
+
+    #[test]
+    fn convert_results_to_pointers() {
+        let _ = crate::take_last_error();
+        let mut value = 42_u32;
+
+        let ok =
+            into_nullable_ptr::<_, NullPointer>(Ok(&mut value as *mut u32));
+        assert_eq!(ok, &mut value as *mut u32);
+        assert!(crate::error_message().is_none());
+
+        let err = into_nullable_ptr::<u32, _>(Err(NullPointer));
+        assert!(err.is_null());
+        assert!(crate::error_handling::last_error_is::<NullPointer>());
+        let _ = crate::take_last_error();
+    }
+
+    #[test]
+    fn nul_is_the_null_char() {
+        const NUL: char = <char as Nullable>::NULL;
+
+        assert_eq!(NUL, '\0');
+        assert!('\0'.is_null());
+        assert!(!'a'.is_null());
+    }
 }
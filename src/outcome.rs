@@ -0,0 +1,113 @@
+//! Returning a value and a status code from the same function.
+//!
+//! Most of this crate assumes a function's return type has some *"obviously
+//! invalid"* value (see [`Nullable`]) which can be used to indicate failure.
+//! That doesn't work when every value in the type's domain is valid, so an
+//! [`Outcome`] lets you return a status code alongside the value instead.
+//!
+//! [`Nullable`]: ../trait.Nullable.html
+//! [`Outcome`]: struct.Outcome.html
+
+use anyhow::Error;
+use libc::c_int;
+
+use crate::{error_handling, Nullable};
+
+/// An ABI-stable pair of status code and value.
+///
+/// A `status` of `0` means the operation succeeded and `value` can be used,
+/// otherwise `value` is meaningless (it'll be [`Nullable::NULL`]) and the
+/// details are available from `LAST_ERROR`.
+///
+/// # Examples
+///
+/// ```rust
+/// use ffi_helpers::Outcome;
+/// use libc::c_int;
+///
+/// #[no_mangle]
+/// pub extern "C" fn checked_divide(a: c_int, b: c_int) -> Outcome<c_int> {
+///     let result = a
+///         .checked_div(b)
+///         .ok_or_else(|| anyhow::anyhow!("Unable to divide {} by {}", a, b));
+///
+///     Outcome::from_result(result, -1)
+/// }
+///
+/// assert_eq!(checked_divide(0, 5), Outcome::ok(0));
+/// assert_eq!(checked_divide(1, 0).status, -1);
+/// ```
+///
+/// [`Nullable::NULL`]: ../trait.Nullable.html#associatedconstant.NULL
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Outcome<T: Copy> {
+    /// `0` on success, otherwise an error code.
+    pub status: c_int,
+    /// The value, only meaningful when `status` is `0`.
+    pub value: T,
+}
+
+impl<T: Copy> Outcome<T> {
+    /// A successful `Outcome`.
+    pub fn ok(value: T) -> Outcome<T> { Outcome { status: 0, value } }
+
+    /// A failed `Outcome` with the provided error code.
+    pub fn err(code: c_int) -> Outcome<T>
+    where
+        T: Nullable,
+    {
+        Outcome {
+            status: code,
+            value: T::NULL,
+        }
+    }
+
+    /// Convert a `Result` into an `Outcome`, updating `LAST_ERROR` and using
+    /// `err_code` as the status if it failed.
+    pub fn from_result<E>(result: Result<T, E>, err_code: c_int) -> Outcome<T>
+    where
+        T: Nullable,
+        E: Into<Error>,
+    {
+        match result {
+            Ok(value) => Outcome::ok(value),
+            Err(e) => {
+                error_handling::update_last_error(e);
+                Outcome::err(err_code)
+            },
+        }
+    }
+
+    /// Did the operation succeed?
+    pub fn is_ok(&self) -> bool { self.status == 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error_handling::*;
+
+    #[test]
+    fn build_a_successful_outcome() {
+        clear_last_error();
+
+        let got = Outcome::from_result(Ok::<_, Error>(0_u32), 42);
+
+        assert!(got.is_ok());
+        assert_eq!(got.value, 0);
+        assert!(error_message().is_none());
+    }
+
+    #[test]
+    fn build_a_failed_outcome() {
+        clear_last_error();
+
+        let got: Outcome<u32> =
+            Outcome::from_result(Err(anyhow::anyhow!("Oops")), 42);
+
+        assert!(!got.is_ok());
+        assert_eq!(got.status, 42);
+        assert_eq!(error_message().unwrap(), "Oops");
+    }
+}
@@ -1,27 +1,71 @@
 use anyhow::Error;
 use std::{
-    any::Any,
-    panic::{self, UnwindSafe},
+    any::{Any, TypeId},
+    fmt::Display,
+    panic::{self, AssertUnwindSafe, UnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        RwLock,
+    },
 };
 use thiserror::Error;
 
-use crate::error_handling;
+use crate::{
+    error_handling::{self, read_config, write_config},
+    Nullable,
+};
 
 const DEFAULT_PANIC_MSG: &str = "The program panicked";
 
+static RETHROW_AFTER_CATCH: AtomicBool = AtomicBool::new(false);
+static PANIC_DOWNCASTS: RwLock<Vec<(TypeId, PanicDowncast)>> =
+    RwLock::new(Vec::new());
+
+type PanicDowncast = fn(&(dyn Any + Send)) -> Option<String>;
+
 /// A convenience macro for running a fallible operation (which may panic) and
 /// returning `Nullable::NULL` if there are any errors.
 ///
 /// This is a simple wrapper around [`catch_panic()`] so if there are any errors
 /// the `LAST_ERROR` will be updated accordingly.
 ///
+/// When `Nullable::NULL` is a valid return value, you can put a different
+/// sentinel before the body (e.g. `catch_panic!(-1; { ... })`) and that will
+/// be returned instead.
+///
 /// # Examples
 ///
-/// TODO: Insert an example or two here
+/// ```rust
+/// use libc::c_int;
+///
+/// #[no_mangle]
+/// pub extern "C" fn divide(a: c_int, b: c_int) -> c_int {
+///     // zero is a perfectly valid result, so use -1 to indicate failure
+///     let quotient = ffi_helpers::catch_panic!(-1; {
+///         if b == 0 {
+///             anyhow::bail!("Division by zero");
+///         }
+///         Ok(a / b)
+///     });
+///
+///     quotient
+/// }
+///
+/// assert_eq!(divide(0, 5), 0);
+/// assert_eq!(divide(1, 0), -1);
+/// # ffi_helpers::error_handling::clear_last_error();
+/// ```
 ///
 /// [`catch_panic()`]: fn.catch_panic.html
 #[macro_export]
 macro_rules! catch_panic {
+    ($sentinel:expr; $body:block) => {{
+        let result = $crate::catch_panic(|| $body);
+        match result {
+            Ok(value) => value,
+            Err(_) => return $sentinel,
+        }
+    }};
     ($($tokens:tt)*) => {{
         let result = $crate::catch_panic(|| { $($tokens)* });
         match result {
@@ -31,26 +75,170 @@ macro_rules! catch_panic {
     }};
 }
 
+/// Export a synchronous, fallible function to C.
+///
+/// The function is written like a normal Rust function whose body evaluates
+/// to a `Result<T, Error>`, where `T` is the declared return type. This
+/// generates an `unsafe extern "C"` wrapper which:
+///
+/// 1. Null-checks every raw pointer argument
+/// 2. Runs the body inside [`catch_panic!()`]
+/// 3. Updates `LAST_ERROR` and returns a sentinel if anything goes wrong
+///
+/// The sentinel is `Nullable::NULL` for the return type unless you put
+/// `sentinel: <expr>;` before the function. This is the synchronous
+/// counterpart to [`export_task!()`].
+///
+/// # Examples
+///
+/// ```rust
+/// use libc::{c_char, c_int};
+/// use std::ffi::CStr;
+///
+/// ffi_helpers::export_fallible! {
+///     /// Parse a number, returning `-1` on failure.
+///     sentinel: -1;
+///     pub fn parse_number(text: *const c_char) -> c_int {
+///         let text = CStr::from_ptr(text).to_str()?;
+///         Ok(text.trim().parse()?)
+///     }
+/// }
+///
+/// # unsafe {
+/// assert_eq!(parse_number(b"42\0".as_ptr() as *const c_char), 42);
+/// assert_eq!(parse_number(b"forty two\0".as_ptr() as *const c_char), -1);
+/// assert_eq!(parse_number(std::ptr::null()), -1);
+/// # }
+/// # ffi_helpers::error_handling::clear_last_error();
+/// ```
+///
+/// [`catch_panic!()`]: macro.catch_panic.html
+/// [`export_task!()`]: macro.export_task.html
+#[macro_export]
+macro_rules! export_fallible {
+    (
+        $( #[$attr:meta] )*
+        sentinel: $sentinel:expr;
+        $vis:vis fn $name:ident($( $arg:ident : $type:ty ),* $(,)?) -> $ret:ty $body:block
+    ) => {
+        #[allow(dead_code)]
+        #[no_mangle]
+        $( #[$attr] )*
+        $vis unsafe extern "C" fn $name($( $arg : $type ),*) -> $ret {
+            #[allow(unused_imports)]
+            use $crate::{OtherArgument as _, PointerArgument as _};
+
+            $(
+                if (&$crate::NullCheck(&$arg)).is_null_pointer() {
+                    $crate::error_handling::update_last_error($crate::NullPointer);
+                    return $sentinel;
+                }
+            )*
+
+            $crate::catch_panic!($sentinel; $body)
+        }
+    };
+    (
+        $( #[$attr:meta] )*
+        $vis:vis fn $name:ident($( $arg:ident : $type:ty ),* $(,)?) -> $ret:ty $body:block
+    ) => {
+        $crate::export_fallible! {
+            $( #[$attr] )*
+            sentinel: <$ret as $crate::Nullable>::NULL;
+            $vis fn $name($( $arg : $type ),*) -> $ret $body
+        }
+    };
+}
+
 /// Try to execute some function, catching any panics and translating them into
 /// errors to make sure Rust doesn't unwind across the FFI boundary.
 ///
 /// If the function returns an error or panics the `Error` is passed into
 /// [`update_last_error()`].
 ///
+/// When the `log` feature is enabled, caught panics are also emitted as a
+/// `log::error!()` record mentioning where `catch_panic()` was called from.
+///
+/// # Warning
+///
+/// Enabling the `no-catch-panic` feature turns this (and everything built on
+/// it) into a plain function call which only handles errors. Panics **will**
+/// unwind across the FFI boundary, which is undefined behaviour. Only use it
+/// when your code can't panic, e.g. because it is compiled with
+/// `panic = "abort"`.
+///
 /// [`update_last_error()`]: fn.update_last_error.html
+#[allow(clippy::result_unit_err)]
+#[cfg_attr(feature = "log", track_caller)]
 pub fn catch_panic<T, F>(func: F) -> Result<T, ()>
 where
     F: FnOnce() -> Result<T, Error> + UnwindSafe,
 {
-    let result = panic::catch_unwind(func)
-        .map_err(|e| {
-            let panic_msg = recover_panic_message(e)
-                .unwrap_or_else(|| DEFAULT_PANIC_MSG.to_string());
-            Error::from(Panic::new(panic_msg))
-        })
-        .and_then(|v| v);
-
-    match result {
+    catch_panic_impl(func, Error::from)
+}
+
+/// A variant of [`catch_panic()`] which keeps the existing `LAST_ERROR` around
+/// when a panic is caught.
+///
+/// Instead of replacing the previous error, the caught [`Panic`] is attached
+/// to it as extra context so the error chain contains both.
+///
+/// [`catch_panic()`]: fn.catch_panic.html
+/// [`Panic`]: struct.Panic.html
+#[allow(clippy::result_unit_err)]
+#[cfg_attr(feature = "log", track_caller)]
+pub fn catch_panic_chaining<T, F>(func: F) -> Result<T, ()>
+where
+    F: FnOnce() -> Result<T, Error> + UnwindSafe,
+{
+    catch_panic_impl(func, |panic| match error_handling::take_last_error() {
+        Some(previous) => previous.context(panic),
+        None => Error::from(panic),
+    })
+}
+
+/// A variant of [`catch_panic()`] which doesn't require the closure to be
+/// `UnwindSafe`.
+///
+/// # Unwind Safety
+///
+/// The closure is wrapped in an [`AssertUnwindSafe`], so it is up to you to
+/// make sure nothing it captures can be observed in a broken state after a
+/// panic. For example, if the closure panics halfway through updating a
+/// `&mut` reference, the caller may see a partially updated value.
+///
+/// Prefer [`catch_panic()`] and only reach for this when you have reasoned
+/// about what happens to the captured state if a panic occurs.
+///
+/// [`catch_panic()`]: fn.catch_panic.html
+/// [`AssertUnwindSafe`]: https://doc.rust-lang.org/std/panic/struct.AssertUnwindSafe.html
+#[allow(clippy::result_unit_err)]
+#[cfg_attr(feature = "log", track_caller)]
+pub fn catch_panic_assert<T, F>(func: F) -> Result<T, ()>
+where
+    F: FnOnce() -> Result<T, Error>,
+{
+    catch_panic_impl(AssertUnwindSafe(func), Error::from)
+}
+
+/// Used by the `#[catch_panics]` attribute to run a function's body, returning
+/// `R::NULL` if it panics.
+#[doc(hidden)]
+pub fn catch_panics_or_null<R, F>(func: F) -> R
+where
+    R: Nullable,
+    F: FnOnce() -> R,
+{
+    catch_panic_assert(|| Ok(func())).unwrap_or(R::NULL)
+}
+
+#[cfg_attr(feature = "log", track_caller)]
+fn catch_panic_impl<T, F, P>(func: F, panic_to_error: P) -> Result<T, ()>
+where
+    F: FnOnce() -> Result<T, Error> + UnwindSafe,
+    P: FnOnce(Panic) -> Error,
+{
+    match capture_panic_impl(func, panic_to_error) {
         Ok(v) => Ok(v),
         Err(e) => {
             error_handling::update_last_error(e);
@@ -59,6 +247,127 @@ where
     }
 }
 
+/// Like [`catch_panic()`], but the error (or caught [`Panic`]) is handed
+/// back to the caller instead of being stored in `LAST_ERROR`.
+///
+/// This is used by background tasks, whose errors are sent to the
+/// `TaskHandle` untouched so the error transform and hooks only see them
+/// once, when they are finally stored on the thread which retrieves them.
+///
+/// [`catch_panic()`]: fn.catch_panic.html
+/// [`Panic`]: struct.Panic.html
+#[cfg_attr(feature = "log", track_caller)]
+pub(crate) fn capture_panic<T, F>(func: F) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, Error> + UnwindSafe,
+{
+    capture_panic_impl(func, Error::from)
+}
+
+#[cfg_attr(feature = "log", track_caller)]
+fn capture_panic_impl<T, F, P>(func: F, panic_to_error: P) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, Error> + UnwindSafe,
+    P: FnOnce(Panic) -> Error,
+{
+    #[cfg(feature = "log")]
+    let caller = std::panic::Location::caller();
+
+    if cfg!(feature = "no-catch-panic") {
+        // the caller has promised nothing will panic, so skip the landing pad
+        func()
+    } else {
+        match panic::catch_unwind(func) {
+            Ok(result) => result,
+            Err(payload) => {
+                let panic_msg = panic_message(&*payload)
+                    .unwrap_or_else(|| DEFAULT_PANIC_MSG.to_string());
+                #[cfg(feature = "log")]
+                log::error!("Caught a panic in {}: {}", caller, panic_msg);
+                let err = panic_to_error(Panic::new(panic_msg));
+
+                if RETHROW_AFTER_CATCH.load(Ordering::SeqCst) {
+                    error_handling::update_last_error(err);
+                    panic::resume_unwind(payload);
+                }
+
+                Err(err)
+            },
+        }
+    }
+}
+
+/// Run a fallible operation (which may panic), returning `sentinel` if it
+/// fails.
+///
+/// This is a variant of [`catch_panic()`] which lets you pick the value to
+/// return on failure instead of relying on `Nullable::NULL`. Either way, panics
+/// and errors will be passed to [`update_last_error()`].
+///
+/// # Examples
+///
+/// ```rust
+/// use libc::c_int;
+///
+/// #[no_mangle]
+/// pub extern "C" fn parse_number(n: c_int) -> c_int {
+///     // zero is a perfectly valid result, so use -1 to indicate failure
+///     ffi_helpers::panic::guard(-1, || {
+///         if n < 0 {
+///             anyhow::bail!("Expected a positive number");
+///         }
+///         Ok(n)
+///     })
+/// }
+///
+/// assert_eq!(parse_number(0), 0);
+/// assert_eq!(parse_number(-5), -1);
+/// ```
+///
+/// [`catch_panic()`]: fn.catch_panic.html
+/// [`update_last_error()`]: ../error_handling/fn.update_last_error.html
+pub fn guard<T, F>(sentinel: T, func: F) -> T
+where
+    F: FnOnce() -> Result<T, Error> + UnwindSafe,
+{
+    catch_panic(func).unwrap_or(sentinel)
+}
+
+/// Restore every panic handling setting to its default.
+///
+/// See also [`error_handling::reset_config()`].
+///
+/// [`error_handling::reset_config()`]: ../error_handling/fn.reset_config.html
+pub fn reset_config() {
+    set_rethrow_after_catch(false);
+    write_config(&PANIC_DOWNCASTS).clear();
+}
+
+pub(crate) fn dump_config(out: &mut String) {
+    use std::fmt::Write;
+
+    let rethrow = RETHROW_AFTER_CATCH.load(Ordering::SeqCst);
+    let _ = writeln!(out, "panic.rethrow_after_catch: {}", rethrow);
+    let downcasts = read_config(&PANIC_DOWNCASTS).len();
+    let _ = writeln!(out, "panic.registered_downcasts: {}", downcasts);
+}
+
+/// Make [`catch_panic()`] (and friends) resume unwinding with the original
+/// panic payload after recording the panic in `LAST_ERROR`.
+///
+/// This lets you get Rust's normal panic behaviour (e.g. a backtrace in the
+/// debugger) while still having any error hooks see the panic.
+///
+/// # Warning
+///
+/// This is **only** intended for debugging. With this enabled panics will
+/// unwind across the FFI boundary, which is undefined behaviour.
+///
+/// [`catch_panic()`]: fn.catch_panic.html
+pub fn set_rethrow_after_catch(rethrow: bool) {
+    RETHROW_AFTER_CATCH.store(rethrow, Ordering::SeqCst);
+}
+
 /// A caught panic message.
 #[derive(Debug, Clone, PartialEq, Error)]
 #[error("Panic: {}", message)]
@@ -84,12 +393,42 @@ impl Panic {
 pub fn recover_panic_message(
     e: Box<dyn Any + Send + 'static>,
 ) -> Option<String> {
+    panic_message(&*e)
+}
+
+/// Teach [`recover_panic_message()`] (and therefore [`catch_panic()`]) how to
+/// get a message from panics whose payload is a `T`, using its `Display`
+/// impl.
+///
+/// This is useful when code uses `std::panic::panic_any()` with a custom
+/// type instead of a string. Registering the same type more than once has no
+/// effect.
+///
+/// [`recover_panic_message()`]: fn.recover_panic_message.html
+/// [`catch_panic()`]: fn.catch_panic.html
+pub fn register_panic_downcast<T>()
+where
+    T: Display + 'static,
+{
+    let type_id = TypeId::of::<T>();
+    let mut downcasts = write_config(&PANIC_DOWNCASTS);
+
+    if downcasts.iter().all(|(id, _)| *id != type_id) {
+        downcasts.push((type_id, |e| {
+            e.downcast_ref::<T>().map(|payload| payload.to_string())
+        }));
+    }
+}
+
+fn panic_message(e: &(dyn Any + Send)) -> Option<String> {
     if let Some(msg) = e.downcast_ref::<String>() {
         Some(msg.clone())
     } else if let Some(msg) = e.downcast_ref::<&str>() {
         Some(msg.to_string())
     } else {
-        None
+        read_config(&PANIC_DOWNCASTS)
+            .iter()
+            .find_map(|(_, downcast)| downcast(e))
     }
 }
 
@@ -98,6 +437,78 @@ mod tests {
     use super::*;
     use crate::error_handling::*;
 
+    export_fallible! {
+        fn checked_sqrt(value: *const f64, out: *mut f64) -> libc::c_int {
+            if *value < 0.0 {
+                anyhow::bail!("Can't take the square root of a negative");
+            }
+            *out = value.read().sqrt();
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn export_a_fallible_function() {
+        let mut out = 0.0;
+
+        let got = unsafe { checked_sqrt(&16.0, &mut out) };
+        assert_eq!(got, 1);
+        assert_eq!(out, 4.0);
+
+        clear_last_error();
+        let got = unsafe { checked_sqrt(&-1.0, &mut out) };
+        assert_eq!(got, 0);
+        assert_eq!(
+            error_message().unwrap(),
+            "Can't take the square root of a negative"
+        );
+
+        let got = unsafe { checked_sqrt(&16.0, std::ptr::null_mut()) };
+        assert_eq!(got, 0);
+        assert!(last_error_is::<crate::NullPointer>());
+        clear_last_error();
+    }
+
+    #[cfg(not(feature = "no-catch-panic"))]
+    #[test]
+    fn the_macro_can_return_a_custom_sentinel() {
+        use libc::c_int;
+
+        fn checked_div(a: c_int, b: c_int) -> c_int {
+            catch_panic!(-1; {
+                if b == 0 {
+                    panic!("Division by zero");
+                }
+                Ok(a / b)
+            })
+        }
+
+        assert_eq!(checked_div(0, 5), 0);
+        assert_eq!(checked_div(1, 0), -1);
+        assert!(last_error_is::<Panic>());
+        clear_last_error();
+    }
+
+    #[test]
+    fn recover_messages_from_registered_payload_types() {
+        struct Custom(u32);
+
+        impl std::fmt::Display for Custom {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "Custom payload #{}", self.0)
+            }
+        }
+
+        let payload = || panic::catch_unwind(|| panic::panic_any(Custom(7)));
+        assert_eq!(recover_panic_message(payload().unwrap_err()), None);
+
+        register_panic_downcast::<Custom>();
+
+        let got = recover_panic_message(payload().unwrap_err());
+        assert_eq!(got.as_deref(), Some("Custom payload #7"));
+    }
+
+    #[cfg(not(feature = "no-catch-panic"))]
     #[test]
     fn able_to_catch_panics_and_recover_the_panic_message() {
         let _ = take_last_error();
@@ -113,4 +524,99 @@ mod tests {
             _ => unreachable!(),
         }
     }
+
+    #[cfg(not(feature = "no-catch-panic"))]
+    #[test]
+    fn assert_variant_accepts_mutable_captures() {
+        let mut counter = 0;
+
+        let got = catch_panic_assert(|| {
+            counter += 1;
+            Ok(counter)
+        });
+        assert_eq!(got, Ok(1));
+
+        let got: Result<(), ()> = catch_panic_assert(|| {
+            counter += 1;
+            panic!("Oops");
+        });
+        assert!(got.is_err());
+        assert_eq!(counter, 2);
+        assert!(take_last_error().unwrap().is::<Panic>());
+    }
+
+    #[cfg(all(feature = "log", not(feature = "no-catch-panic")))]
+    #[test]
+    fn caught_panics_are_logged() {
+        use log::{Log, Metadata, Record};
+        use std::sync::Mutex;
+
+        struct Capture(Mutex<Vec<String>>);
+
+        impl Log for Capture {
+            fn enabled(&self, _: &Metadata<'_>) -> bool { true }
+
+            fn log(&self, record: &Record<'_>) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+
+            fn flush(&self) {}
+        }
+
+        static LOGGER: Capture = Capture(Mutex::new(Vec::new()));
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Error);
+
+        let _: Result<(), ()> = catch_panic(|| panic!("Logged panic"));
+
+        let records = LOGGER.0.lock().unwrap();
+        let record =
+            records.iter().find(|r| r.contains("Logged panic")).unwrap();
+        assert!(record.contains(file!()));
+    }
+
+    #[cfg(not(feature = "no-catch-panic"))]
+    #[test]
+    fn chain_caught_panics_onto_the_previous_error() {
+        let _ = take_last_error();
+        update_last_error(anyhow::anyhow!("Previous error"));
+
+        let got: Result<(), ()> = catch_panic_chaining(|| panic!("Oops"));
+        assert!(got.is_err());
+
+        let err = take_last_error().unwrap();
+        assert_eq!(err.downcast_ref::<Panic>().unwrap().message, "Oops");
+        assert_eq!(format!("{:#}", err), "Panic: Oops: Previous error");
+    }
+
+    #[cfg(not(feature = "no-catch-panic"))]
+    #[test]
+    fn guard_returns_the_sentinel_on_panic() {
+        let _ = take_last_error();
+
+        let got = guard(-1, || panic!("Oops"));
+
+        assert_eq!(got, -1);
+        assert!(take_last_error().unwrap().downcast_ref::<Panic>().is_some());
+    }
+
+    #[test]
+    fn guard_returns_the_sentinel_on_error() {
+        let _ = take_last_error();
+
+        let got = guard(-1, || Err(anyhow::anyhow!("Something went wrong")));
+
+        assert_eq!(got, -1);
+        assert_eq!(error_message().unwrap(), "Something went wrong");
+    }
+
+    #[test]
+    fn guard_passes_through_successful_values() {
+        let _ = take_last_error();
+
+        let got = guard(-1, || Ok(0));
+
+        assert_eq!(got, 0);
+        assert!(take_last_error().is_none());
+    }
 }
@@ -1,4 +1,10 @@
-use std::ffi::c_void;
+use std::{
+    alloc::{self, Layout},
+    ffi::c_void,
+    marker::PhantomData,
+    ptr::{self, NonNull},
+};
+use thiserror::Error;
 
 /// Splits a closure into its data part and its code part, allowing it to be
 /// used as a callback by FFI code.
@@ -33,7 +39,7 @@ use std::ffi::c_void;
 ///     some_c_function(42, callback, state);
 /// }
 ///
-/// assert_eq!(total, (0..42).sum());
+/// assert_eq!(total, (0..42).sum::<usize>());
 /// ```
 ///
 /// # Safety
@@ -49,6 +55,197 @@ where
     (closure as *mut C as *mut c_void, C::trampoline())
 }
 
+/// Like [`split_closure()`], except the returned [`ScopedCallback`] borrows
+/// the closure so the borrow checker can make sure it isn't used after the
+/// closure (or anything it captured) goes away.
+///
+/// This is intended for the common case where a C function only uses the
+/// callback for the duration of a single call.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::ffi::c_void;
+///
+/// type Callback = unsafe extern "C" fn(*mut c_void, i32);
+///
+/// unsafe fn call_three_times(cb: Callback, user_data: *mut c_void) {
+///     for i in 0..3 {
+///         cb(user_data, i);
+///     }
+/// }
+///
+/// let mut seen = Vec::new();
+/// let mut closure = |n: i32| seen.push(n);
+///
+/// let scoped = ffi_helpers::split_closure_scoped(&mut closure);
+/// unsafe { call_three_times(scoped.callback(), scoped.user_data()) };
+///
+/// assert_eq!(seen, [0, 1, 2]);
+/// ```
+///
+/// The callback can't outlive the closure it was created from.
+///
+/// ```rust,compile_fail
+/// let scoped = {
+///     let mut closure = |n: i32| n + 1;
+///     ffi_helpers::split_closure_scoped(&mut closure)
+/// };
+/// # let _ = scoped;
+/// ```
+///
+/// [`split_closure()`]: fn.split_closure.html
+/// [`ScopedCallback`]: struct.ScopedCallback.html
+pub fn split_closure_scoped<'a, C, Args, Ret>(
+    closure: &'a mut C,
+) -> ScopedCallback<'a, C::Trampoline>
+where
+    C: Split<Args, Ret>,
+{
+    ScopedCallback {
+        callback: C::trampoline(),
+        user_data: closure as *mut C as *mut c_void,
+        _closure: PhantomData,
+    }
+}
+
+/// A callback created by [`split_closure_scoped()`] which is only valid while
+/// the closure it was created from is borrowed.
+///
+/// [`split_closure_scoped()`]: fn.split_closure_scoped.html
+#[derive(Debug)]
+pub struct ScopedCallback<'a, T> {
+    callback: T,
+    user_data: *mut c_void,
+    _closure: PhantomData<&'a mut ()>,
+}
+
+impl<T: Copy> ScopedCallback<'_, T> {
+    /// The function pointer to give to C.
+    pub fn callback(&self) -> T { self.callback }
+
+    /// The state pointer which must be passed to [`callback()`] whenever it
+    /// is called.
+    ///
+    /// [`callback()`]: #method.callback
+    pub fn user_data(&self) -> *mut c_void { self.user_data }
+}
+
+/// A closure which has been moved to the heap so it can be handed to C as a
+/// callback, staying alive until it is explicitly unregistered.
+///
+/// Unlike [`split_closure()`], you don't need to keep the closure alive
+/// yourself. Instead, the closure is intentionally leaked when it is
+/// registered and only reclaimed when you call
+/// [`RegisteredCallback::unregister()`]. Forgetting to unregister it will leak
+/// the closure (and anything it captured).
+///
+/// # Examples
+///
+/// ```rust
+/// use ffi_helpers::RegisteredCallback;
+/// use std::ffi::c_void;
+///
+/// type Callback = unsafe extern "C" fn(*mut c_void, i32) -> i32;
+///
+/// let offset = 10;
+/// let registered: RegisteredCallback<Callback> =
+///     RegisteredCallback::register(move |n: i32| n + offset);
+///
+/// // pass these to the C library, then later on...
+/// let (callback, user_data) = (registered.callback(), registered.user_data());
+/// assert_eq!(unsafe { callback(user_data, 5) }, 15);
+///
+/// // ... once the C library promises to never call the callback again
+/// unsafe { registered.unregister() };
+/// ```
+///
+/// [`split_closure()`]: fn.split_closure.html
+/// [`RegisteredCallback::unregister()`]: #method.unregister
+#[derive(Debug)]
+pub struct RegisteredCallback<T> {
+    callback: T,
+    user_data: *mut c_void,
+    destroy: unsafe fn(*mut c_void),
+}
+
+impl<T: Copy> RegisteredCallback<T> {
+    /// Move the closure to the heap and get a trampoline which can invoke it.
+    pub fn register<C, Args, Ret>(closure: C) -> RegisteredCallback<T>
+    where
+        C: Split<Args, Ret, Trampoline = T> + 'static,
+    {
+        RegisteredCallback {
+            callback: C::trampoline(),
+            user_data: Box::into_raw(Box::new(closure)) as *mut c_void,
+            destroy: destroy_closure::<C>,
+        }
+    }
+
+    /// Like [`register()`], but returns an [`AllocError`] instead of
+    /// aborting if the closure can't be moved to the heap.
+    ///
+    /// This lets code running in memory-constrained environments report
+    /// allocation failure across the FFI boundary gracefully.
+    ///
+    /// [`register()`]: #method.register
+    /// [`AllocError`]: struct.AllocError.html
+    pub fn try_register<C, Args, Ret>(
+        closure: C,
+    ) -> Result<RegisteredCallback<T>, AllocError>
+    where
+        C: Split<Args, Ret, Trampoline = T> + 'static,
+    {
+        let layout = Layout::new::<C>();
+        let ptr = if layout.size() == 0 {
+            NonNull::<C>::dangling().as_ptr()
+        } else {
+            unsafe { alloc::alloc(layout) as *mut C }
+        };
+
+        if ptr.is_null() {
+            return Err(AllocError);
+        }
+
+        // this is the same layout Box uses, so destroy_closure() can free it
+        unsafe { ptr::write(ptr, closure) };
+
+        Ok(RegisteredCallback {
+            callback: C::trampoline(),
+            user_data: ptr as *mut c_void,
+            destroy: destroy_closure::<C>,
+        })
+    }
+
+    /// The function pointer to give to C.
+    pub fn callback(&self) -> T { self.callback }
+
+    /// The state pointer which must be passed to [`callback()`] whenever it
+    /// is called.
+    ///
+    /// [`callback()`]: #method.callback
+    pub fn user_data(&self) -> *mut c_void { self.user_data }
+
+    /// Reclaim the closure, dropping it and anything it captured.
+    ///
+    /// # Safety
+    ///
+    /// The C code must not call the callback (or use its
+    /// [`user_data()`][user_data] pointer) after this.
+    ///
+    /// [user_data]: #method.user_data
+    pub unsafe fn unregister(self) { (self.destroy)(self.user_data); }
+}
+
+unsafe fn destroy_closure<C>(user_data: *mut c_void) {
+    drop(Box::from_raw(user_data as *mut C));
+}
+
+/// There wasn't enough memory to move a closure to the heap.
+#[derive(Debug, Copy, Clone, PartialEq, Error)]
+#[error("Unable to allocate memory for the closure")]
+pub struct AllocError;
+
 /// A helper trait used by [`split_closure()`] to get a trampoline function
 /// which will invoke the closure.
 ///
@@ -107,3 +304,64 @@ impl_split!(A, B, C, D, E, F, G, H, I, K, L; A, B, C, D, E, F, G, H, I, K, L);
 impl_split!(A, B, C, D, E, F, G, H, I, K, L, M; A, B, C, D, E, F, G, H, I, K, L, M);
 impl_split!(A, B, C, D, E, F, G, H, I, K, L, M, N; A, B, C, D, E, F, G, H, I, K, L, M, N);
 impl_split!(A, B, C, D, E, F, G, H, I, K, L, M, N, O; A, B, C, D, E, F, G, H, I, K, L, M, N, O);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::Cell, rc::Rc};
+
+    #[test]
+    fn registered_callbacks_are_dropped_when_unregistered() {
+        struct SetOnDrop(Rc<Cell<bool>>);
+
+        impl Drop for SetOnDrop {
+            fn drop(&mut self) { self.0.set(true); }
+        }
+
+        type Callback = unsafe extern "C" fn(*mut c_void, usize) -> usize;
+
+        let dropped = Rc::new(Cell::new(false));
+        let flag = SetOnDrop(Rc::clone(&dropped));
+        let mut calls = 0;
+        let registered: RegisteredCallback<Callback> =
+            RegisteredCallback::register(move |n: usize| {
+                let _ = &flag;
+                calls += 1;
+                n * calls
+            });
+
+        unsafe {
+            let cb = registered.callback();
+            assert_eq!(cb(registered.user_data(), 5), 5);
+            assert_eq!(cb(registered.user_data(), 5), 10);
+            assert!(!dropped.get());
+
+            registered.unregister();
+        }
+
+        assert!(dropped.get());
+    }
+
+    #[test]
+    fn try_register_succeeds_when_memory_is_available() {
+        type Callback = unsafe extern "C" fn(*mut c_void, u32) -> u32;
+
+        let offset = 10;
+        let with_state: Result<RegisteredCallback<Callback>, AllocError> =
+            RegisteredCallback::try_register(move |n: u32| n + offset);
+        let zero_sized: Result<RegisteredCallback<Callback>, AllocError> =
+            RegisteredCallback::try_register(|n: u32| n * 2);
+
+        unsafe {
+            let with_state = with_state.unwrap();
+            let cb = with_state.callback();
+            assert_eq!(cb(with_state.user_data(), 5), 15);
+            with_state.unregister();
+
+            let zero_sized = zero_sized.unwrap();
+            let cb = zero_sized.callback();
+            assert_eq!(cb(zero_sized.user_data(), 5), 10);
+            zero_sized.unregister();
+        }
+    }
+}
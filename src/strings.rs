@@ -0,0 +1,259 @@
+//! Helpers for passing strings across the FFI boundary.
+//!
+//! These use the same conventions as the `error_message_*()` functions in
+//! [`error_handling`], so the host only needs to learn one way of reading
+//! strings. See [`export_string_getter!()`] for a convenient way to expose a
+//! string field on an opaque type.
+//!
+//! [`error_handling`]: ../error_handling/index.html
+//! [`export_string_getter!()`]: ../macro.export_string_getter.html
+
+use crate::{
+    error_handling::{buffer_from_raw, COPY_INVALID_LENGTH},
+    nullable::Nullable,
+};
+use libc::{c_char, c_int};
+use std::borrow::Cow;
+
+/// Copy a string into the provided buffer as a UTF-8 encoded string.
+///
+/// This returns the number of bytes written (including the trailing null). If
+/// the buffer is too small, the negative of the required length is returned
+/// instead, so `-42` means you need a buffer of 42 bytes.
+///
+/// # Safety
+///
+/// `buf` must point to a writable buffer of at least `length` bytes.
+pub unsafe fn copy_utf8(value: &str, buf: *mut c_char, length: c_int) -> c_int {
+    crate::null_pointer_check!(buf);
+    let buffer = match buffer_from_raw(buf as *mut u8, length) {
+        Some(buffer) => buffer,
+        None => return COPY_INVALID_LENGTH,
+    };
+
+    copy_str_into_buffer(value, buffer, true, |s| s.as_bytes().into())
+}
+
+/// Copy a string into the provided buffer as a UTF-16 encoded string.
+///
+/// Like [`error_message_utf16()`], this returns the number of `u16`s written
+/// (including the trailing null) and if the buffer is too small, the negative
+/// of the required length in `u16`s.
+///
+/// # Safety
+///
+/// `buf` must point to a writable buffer of at least `length` `u16`s.
+///
+/// [`error_message_utf16()`]: ../error_handling/fn.error_message_utf16.html
+pub unsafe fn copy_utf16(value: &str, buf: *mut u16, length: c_int) -> c_int {
+    crate::null_pointer_check!(buf);
+    let buffer = match buffer_from_raw(buf, length) {
+        Some(buffer) => buffer,
+        None => return COPY_INVALID_LENGTH,
+    };
+
+    copy_str_into_buffer(value, buffer, true, |s| {
+        s.encode_utf16().collect::<Vec<_>>().into()
+    })
+}
+
+/// Copy a string into the caller's buffer after converting it to the
+/// caller's encoding with `encode`.
+///
+/// This is the "size then fill" logic shared by every function which hands a
+/// string back to the caller. If the buffer is too small nothing is written
+/// and the negative of the required length is returned, otherwise the number
+/// of elements written.
+pub(crate) fn copy_str_into_buffer<B, F>(
+    value: &str,
+    buffer: &mut [B],
+    trailing_null: bool,
+    encode: F,
+) -> c_int
+where
+    F: FnOnce(&str) -> Cow<'_, [B]>,
+    B: Copy + Nullable,
+{
+    copy_into_buffer(&encode(value), buffer, trailing_null)
+}
+
+/// Copy some already-encoded data into the caller's buffer, following the
+/// same convention as [`copy_str_into_buffer()`].
+///
+/// [`copy_str_into_buffer()`]: fn.copy_str_into_buffer.html
+pub(crate) fn copy_into_buffer<B>(
+    data: &[B],
+    buffer: &mut [B],
+    trailing_null: bool,
+) -> c_int
+where
+    B: Copy + Nullable,
+{
+    let required = if trailing_null {
+        data.len() + 1
+    } else {
+        data.len()
+    };
+
+    if required > buffer.len() {
+        // buffer isn't big enough, tell the caller how much room we need
+        return buffer_too_small(required);
+    }
+
+    buffer[..data.len()].copy_from_slice(data);
+    if trailing_null {
+        // Make sure to add a trailing null in case people use this as a bare
+        // char*
+        buffer[data.len()] = B::NULL;
+    }
+
+    required as c_int
+}
+
+/// The value returned when a buffer is too small, i.e. the negative of the
+/// `required` length.
+///
+/// Lengths which don't fit in a `c_int` are capped at `c_int::MAX` so the
+/// result can never overflow or be mistaken for [`COPY_INVALID_LENGTH`].
+///
+/// [`COPY_INVALID_LENGTH`]: ../error_handling/constant.COPY_INVALID_LENGTH.html
+pub(crate) fn buffer_too_small(required: usize) -> c_int {
+    -c_int::try_from(required).unwrap_or(c_int::MAX)
+}
+
+/// Generate a pair of functions for reading a string from an opaque type as
+/// UTF-8 and UTF-16.
+///
+/// The `getter` looks like a closure which is given a reference to the object
+/// and may evaluate to anything which implements `AsRef<str>` (e.g. a `&str`
+/// or `String`). The generated functions take a pointer to the object
+/// followed by a buffer and its length, and behave like [`copy_utf8()`] and
+/// [`copy_utf16()`]. If the object pointer is null `LAST_ERROR` is updated
+/// and `0` is returned.
+///
+/// # Examples
+///
+/// ```rust
+/// use ffi_helpers::export_string_getter;
+/// use libc::c_char;
+///
+/// pub struct Person {
+///     name: String,
+/// }
+///
+/// export_string_getter! {
+///     Type: Person;
+///     getter: |person| person.name.as_str();
+///     utf8: person_name_utf8;
+///     utf16: person_name_utf16;
+/// }
+///
+/// let person = Person { name: String::from("Ferris") };
+/// let mut buffer = [0 as c_char; 16];
+///
+/// let got = unsafe {
+///     person_name_utf8(&person, buffer.as_mut_ptr(), buffer.len() as _)
+/// };
+///
+/// assert_eq!(got, 7);
+/// ```
+///
+/// [`copy_utf8()`]: strings/fn.copy_utf8.html
+/// [`copy_utf16()`]: strings/fn.copy_utf16.html
+#[macro_export]
+macro_rules! export_string_getter {
+    (
+        $( #[$attr:meta] )*
+        Type: $Type:ty;
+        getter: |$obj:ident| $getter:expr;
+        utf8: $utf8:ident;
+        utf16: $utf16:ident;
+    ) => {
+        /// Copy the string into the provided buffer as UTF-8.
+        #[allow(dead_code)]
+        #[no_mangle]
+        $( #[$attr] )*
+        pub unsafe extern "C" fn $utf8(obj: *const $Type, buf: *mut ::std::os::raw::c_char, length: ::std::os::raw::c_int) -> ::std::os::raw::c_int {
+            $crate::null_pointer_check!(obj);
+            let $obj: &$Type = &*obj;
+            let value = $getter;
+            $crate::strings::copy_utf8(::std::convert::AsRef::<str>::as_ref(&value), buf, length)
+        }
+
+        /// Copy the string into the provided buffer as UTF-16.
+        #[allow(dead_code)]
+        #[no_mangle]
+        $( #[$attr] )*
+        pub unsafe extern "C" fn $utf16(obj: *const $Type, buf: *mut u16, length: ::std::os::raw::c_int) -> ::std::os::raw::c_int {
+            $crate::null_pointer_check!(obj);
+            let $obj: &$Type = &*obj;
+            let value = $getter;
+            $crate::strings::copy_utf16(::std::convert::AsRef::<str>::as_ref(&value), buf, length)
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc::c_char;
+
+    #[test]
+    fn copy_a_string_into_buffers_of_various_sizes() {
+        let mut buffer = [0x7f as c_char; 8];
+
+        for length in 0..6 {
+            let got =
+                unsafe { copy_utf8("Hello", buffer.as_mut_ptr(), length) };
+            assert_eq!(got, -6, "A {} byte buffer is too small", length);
+        }
+        // nothing is written unless the whole string fits
+        assert_eq!(buffer, [0x7f; 8]);
+
+        let got = unsafe { copy_utf8("Hello", buffer.as_mut_ptr(), 8) };
+        assert_eq!(got, 6);
+        let expected = b"Hello\0\x7f\x7f".map(|b| b as c_char);
+        assert_eq!(buffer, expected);
+    }
+
+    #[test]
+    fn huge_required_lengths_dont_overflow() {
+        assert_eq!(buffer_too_small(42), -42);
+        assert_eq!(buffer_too_small(usize::MAX), -c_int::MAX);
+    }
+
+    pub struct Person {
+        name: String,
+    }
+
+    export_string_getter! {
+        Type: Person;
+        getter: |person| format!("Dr. {}", person.name);
+        utf8: person_name_utf8;
+        utf16: person_name_utf16;
+    }
+
+    #[test]
+    fn read_a_field_as_utf8_and_utf16() {
+        let person = Person {
+            name: String::from("Ferris"),
+        };
+        let mut utf8 = [0 as c_char; 16];
+        let mut utf16 = [0_u16; 16];
+
+        let (got_utf8, got_utf16, too_small) = unsafe {
+            (
+                person_name_utf8(&person, utf8.as_mut_ptr(), 16),
+                person_name_utf16(&person, utf16.as_mut_ptr(), 16),
+                person_name_utf8(&person, utf8.as_mut_ptr(), 4),
+            )
+        };
+
+        assert_eq!(got_utf8, 11);
+        let utf8: Vec<u8> = utf8[..10].iter().map(|&c| c as u8).collect();
+        assert_eq!(utf8, b"Dr. Ferris");
+        assert_eq!(got_utf16, 11);
+        assert_eq!(String::from_utf16(&utf16[..10]).unwrap(), "Dr. Ferris");
+        assert_eq!(too_small, -11);
+    }
+}
@@ -127,19 +127,207 @@
 //! [`export_task!()`]: ../macro.export_task.html
 
 use anyhow::Error;
+#[cfg(all(unix, feature = "signal"))]
+use libc::c_int;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
 use std::{
-    panic::UnwindSafe,
+    any::{self, Any},
+    collections::HashMap,
+    mem,
+    panic::{AssertUnwindSafe, UnwindSafe},
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
         mpsc::{self, Receiver, TryRecvError},
-        Arc,
+        Arc, Mutex, MutexGuard, OnceLock, PoisonError, TryLockError, Weak,
     },
     thread,
+    time::{Duration, Instant},
 };
 use thiserror::Error;
 
+#[cfg(unix)]
+use self::notify::Notifier;
+#[cfg(all(unix, feature = "signal"))]
+use self::signal::SignalWatch;
 use crate::{error_handling, panic};
 
+static ACTIVE_TASKS: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of tasks currently running in the background, across every
+/// [`TaskHandle`].
+///
+/// [`TaskHandle`]: struct.TaskHandle.html
+pub fn active_task_count() -> usize { ACTIVE_TASKS.load(Ordering::SeqCst) }
+
+static LEAKED_TASKS: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of tasks which had already been cancelled but were still
+/// running when every [`TaskHandle`] pointing to them was dropped.
+///
+/// A task which never checks its [`CancellationToken`] can't be stopped, and
+/// will stay in this count (and keep its thread alive) until it finishes by
+/// itself. Tasks which were still running normally when their last handle
+/// was dropped aren't counted, because dropping the handle cancels them and
+/// they are expected to stop shortly afterwards.
+///
+/// [`TaskHandle`]: struct.TaskHandle.html
+/// [`CancellationToken`]: struct.CancellationToken.html
+pub fn leaked_task_count() -> usize { LEAKED_TASKS.load(Ordering::SeqCst) }
+
+/// The task is still running and has at least one [`TaskHandle`].
+///
+/// [`TaskHandle`]: struct.TaskHandle.html
+const RUNNING: u8 = 0;
+/// The task has finished running.
+const FINISHED: u8 = 1;
+/// Every [`TaskHandle`] was dropped while the task was still running.
+///
+/// [`TaskHandle`]: struct.TaskHandle.html
+const DETACHED: u8 = 2;
+
+/// The number of tasks of each type (keyed by `type_name()`) which are
+/// currently running.
+fn active_by_type() -> &'static Mutex<HashMap<&'static str, usize>> {
+    static ACTIVE: OnceLock<Mutex<HashMap<&'static str, usize>>> =
+        OnceLock::new();
+    ACTIVE.get_or_init(Default::default)
+}
+
+/// The number of `K` tasks currently running in the background.
+///
+/// Like [`active_task_count()`], but broken down by task type. Type-erased
+/// tasks and closures passed to [`spawn_fn()`] aren't counted.
+///
+/// [`active_task_count()`]: fn.active_task_count.html
+/// [`spawn_fn()`]: fn.spawn_fn.html
+pub fn active_count_for<K: Task>() -> usize {
+    lock(active_by_type())
+        .get(any::type_name::<K>())
+        .copied()
+        .unwrap_or(0)
+}
+
+/// Run a task, making sure it is included in [`active_count_for()`] while it
+/// is running.
+///
+/// [`active_count_for()`]: fn.active_count_for.html
+pub(crate) fn run_counted<K: Task>(
+    task: &K,
+    cancel_tok: &CancellationToken,
+) -> Result<K::Output, Error> {
+    let _active = ActiveTask::start(any::type_name::<K>());
+    task.run(cancel_tok)
+}
+
+/// Keeps a task type's entry in `active_by_type()` up to date, even if the
+/// task panics.
+struct ActiveTask {
+    name: &'static str,
+}
+
+impl ActiveTask {
+    fn start(name: &'static str) -> ActiveTask {
+        *lock(active_by_type()).entry(name).or_insert(0) += 1;
+        ActiveTask { name }
+    }
+}
+
+impl Drop for ActiveTask {
+    fn drop(&mut self) {
+        if let Some(count) = lock(active_by_type()).get_mut(self.name) {
+            *count -= 1;
+        }
+    }
+}
+
+/// Every task which still has a [`TaskHandle`], so they can all be cancelled
+/// at once.
+///
+/// [`TaskHandle`]: struct.TaskHandle.html
+static LIVE_TASKS: Mutex<Vec<Weak<dyn Cancel + Send + Sync>>> =
+    Mutex::new(Vec::new());
+
+/// Cancel every task which was spawned using a [`TaskHandle`] and still has a
+/// handle pointing to it.
+///
+/// This is handy when the host is shutting down and doesn't want background
+/// threads to keep running.
+///
+/// [`TaskHandle`]: struct.TaskHandle.html
+pub fn cancel_all_tasks() {
+    let live: Vec<_> =
+        lock(&LIVE_TASKS).iter().filter_map(Weak::upgrade).collect();
+
+    // the lock has already been released, so it's okay if this was the last
+    // reference to a task and dropping it deregisters the task
+    for task in live {
+        task.cancel();
+    }
+}
+
+/// Something which can be cancelled by [`cancel_all_tasks()`].
+///
+/// [`cancel_all_tasks()`]: fn.cancel_all_tasks.html
+trait Cancel {
+    fn cancel(&self);
+}
+
+/// The default stack size for tasks' background threads, where `0` means the
+/// OS default.
+static DEFAULT_STACK_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Set the stack size (in bytes) used for background threads when spawning a
+/// task, or `None` to use the OS default.
+///
+/// This doesn't affect tasks spawned with
+/// [`TaskHandle::spawn_with_stack_size()`].
+///
+/// [`TaskHandle::spawn_with_stack_size()`]: struct.TaskHandle.html#method.spawn_with_stack_size
+pub fn set_default_stack_size(stack_size: Option<usize>) {
+    DEFAULT_STACK_SIZE.store(stack_size.unwrap_or(0), Ordering::SeqCst);
+}
+
+pub(crate) fn dump_config(out: &mut String) {
+    use std::fmt::Write;
+
+    let _ = match default_stack_size() {
+        Some(stack_size) => {
+            writeln!(out, "task.default_stack_size: {} bytes", stack_size)
+        },
+        None => writeln!(out, "task.default_stack_size: OS default"),
+    };
+}
+
+fn default_stack_size() -> Option<usize> {
+    match DEFAULT_STACK_SIZE.load(Ordering::SeqCst) {
+        0 => None,
+        stack_size => Some(stack_size),
+    }
+}
+
+#[cfg(feature = "async")]
+mod future;
+mod group;
+mod guard;
+mod limiter;
+#[cfg(unix)]
+mod notify;
+#[cfg(feature = "serde")]
+mod serialize;
+#[cfg(all(unix, feature = "signal"))]
+mod signal;
+
+#[cfg(feature = "async")]
+pub use self::future::FutureTask;
+#[doc(hidden)]
+pub use self::guard::{release_pointer, track_pointer};
+#[cfg(feature = "serde")]
+pub use self::serialize::SerializedTaskHandle;
+pub use self::{
+    group::TaskGroup, guard::UnknownPointer, limiter::ConcurrencyLimiter,
+};
+
 /// Convenience macro to define the FFI bindings for working with a [`Task`].
 ///
 /// This is implemented as an incremental TT muncher which lets you define the
@@ -148,36 +336,114 @@ use crate::{error_handling, panic};
 /// - `spawn`: The function for spawning a task on a background thread,
 ///   returning a [`TaskHandle`]
 /// - `poll`: A function for receiving the result if it's available
+/// - `poll_with_code`: Like `poll`, but also writes the error code (see
+///   [`last_error_code()`]) through an out-pointer, or `0` if there was no
+///   error
 /// - `wait`: Block the current thread until we get either a result or an error
 /// - `cancel`: Cancel the background task
 /// - `cancelled`: Has the task already been cancelled?
+/// - `cancelled_bool`: Like `cancelled`, but returning a [`FfiBool`]
+/// - `elapsed_ms`: How long the task has been running (or took to run), in
+///   milliseconds
 /// - `result_destroy`: A destructor for the task's result
 /// - `handle_destroy`: A destructor for the [`TaskHandle`], for cleaning up the
 ///   task once you're done with it
 ///
+/// Writing `mode: synchronous;` immediately before `spawn` makes the generated
+/// function run the task on the calling thread (see
+/// [`TaskHandle::run_inline()`]) so its result is ready for the very next
+/// `poll` or `wait`. This makes it much easier to step through a task's logic
+/// in a debugger.
+///
+/// When the `guard-handles` feature is enabled, the destructors (and `wait`)
+/// keep track of which pointers are still alive. Destroying the same pointer
+/// twice will then set `LAST_ERROR` to an [`UnknownPointer`] error instead of
+/// triggering a double-free. This is intended as a debugging aid.
+///
 /// You'll always need to provide the concrete [`Task`] type in the macro's
 /// first "argument".
 ///
+/// The generated functions are `pub` by default. If you'd like to control
+/// their visibility (e.g. to generate them in a private module and re-export
+/// them selectively) you can put something like `vis: pub(crate);` before the
+/// `Task`. Use `vis: pub(self);` for private functions.
+///
+/// # Symbol Names
+///
+/// Every generated function is `#[no_mangle]`, so its name must be unique.
+/// The compiler will reject a crate which exports the same name twice (even
+/// from different modules) with a *"symbol `...` is already defined"* error
+/// pointing at the second definition.
+///
+/// ```rust,compile_fail
+/// # use ffi_helpers::{task::CancellationToken, Task};
+/// # #[derive(Clone)]
+/// # struct Spin;
+/// # impl Task for Spin {
+/// #     type Output = ();
+/// #     fn run(&self, _: &CancellationToken) -> Result<(), anyhow::Error> {
+/// #         Ok(())
+/// #     }
+/// # }
+/// mod first {
+///     ffi_helpers::export_task! {
+///         Task: super::Spin;
+///         spawn: spin_spawn;
+///     }
+/// }
+///
+/// mod second {
+///     ffi_helpers::export_task! {
+///         Task: super::Spin;
+///         spawn: spin_spawn; // error: symbol `spin_spawn` is already defined
+///     }
+/// }
+/// # fn main() {}
+/// ```
+///
+/// The linker can't tell you where a collision between two different crates
+/// comes from, so libraries which get linked together should prefix their
+/// names (e.g. `mylib_spin_spawn`).
+///
 /// [`Task`]: task/trait.Task.html
 /// [`TaskHandle`]: task/struct.TaskHandle.html
+/// [`TaskHandle::run_inline()`]: task/struct.TaskHandle.html#method.run_inline
+/// [`FfiBool`]: enum.FfiBool.html
+/// [`last_error_code()`]: error_handling/fn.last_error_code.html
+/// [`UnknownPointer`]: task/struct.UnknownPointer.html
 #[macro_export]
 macro_rules! export_task {
-    ($( #[$attr:meta] )* Task: $Task:ty; spawn: $spawn:ident; $( $tokens:tt )*) => {
+    (@[$vis:vis] $( #[$attr:meta] )* Task: $Task:ty; spawn: $spawn:ident; $( $tokens:tt )*) => {
         /// Spawn a task in the background, returning a pointer to the task
         /// handle.
         #[allow(dead_code)]
         #[no_mangle]
         $( #[$attr] )*
-        pub unsafe extern "C" fn $spawn(task: *const $Task) -> *mut $crate::task::TaskHandle<<$Task as $crate::Task>::Output> {
+        $vis unsafe extern "C" fn $spawn(task: *const $Task) -> *mut $crate::task::TaskHandle<<$Task as $crate::Task>::Output> {
             $crate::null_pointer_check!(task);
             let task = (&*task).clone();
             let handle = $crate::task::TaskHandle::spawn(task);
-            Box::into_raw(Box::new(handle))
+            $crate::task::track_pointer(Box::into_raw(Box::new(handle)))
+        }
+
+        $crate::export_task!(@[$vis] $( #[$attr] )* Task: $Task; $( $tokens )*);
+    };
+    (@[$vis:vis] $( #[$attr:meta] )* Task: $Task:ty; mode: synchronous; spawn: $spawn:ident; $( $tokens:tt )*) => {
+        /// Run a task to completion on the calling thread, returning a pointer
+        /// to a task handle whose result is already available.
+        #[allow(dead_code)]
+        #[no_mangle]
+        $( #[$attr] )*
+        $vis unsafe extern "C" fn $spawn(task: *const $Task) -> *mut $crate::task::TaskHandle<<$Task as $crate::Task>::Output> {
+            $crate::null_pointer_check!(task);
+            let task = (&*task).clone();
+            let handle = $crate::task::TaskHandle::run_inline(task);
+            $crate::task::track_pointer(Box::into_raw(Box::new(handle)))
         }
 
-        $crate::export_task!($( #[$attr] )* Task: $Task; $( $tokens )*);
+        $crate::export_task!(@[$vis] $( #[$attr] )* Task: $Task; $( $tokens )*);
     };
-    ($( #[$attr:meta] )* Task: $Task:ty; poll: $poll:ident; $( $tokens:tt )*) => {
+    (@[$vis:vis] $( #[$attr:meta] )* Task: $Task:ty; poll: $poll:ident; $( $tokens:tt )*) => {
         /// Poll the task handle and retrieve the result it's ready.
         ///
         /// # Note
@@ -186,14 +452,15 @@ macro_rules! export_task {
         /// an error. If there is an error, we update the last error accordingly.
         ///
         /// You probably want to call `ffi_helpers::error_handling::clear_last_error()`
-        /// beforehand to make sure there
+        /// beforehand to make sure there isn't a stale error from an earlier
+        /// call.
         #[allow(dead_code)]
         #[no_mangle]
         $( #[$attr] )*
-        pub unsafe extern "C" fn $poll(handle: *mut $crate::task::TaskHandle<<$Task as $crate::Task>::Output>) -> *mut <$Task as $crate::Task>::Output {
+        $vis unsafe extern "C" fn $poll(handle: *mut $crate::task::TaskHandle<<$Task as $crate::Task>::Output>) -> *mut <$Task as $crate::Task>::Output {
             $crate::null_pointer_check!(handle);
             match (&*handle).poll() {
-                Some(Ok(value)) => Box::into_raw(Box::new(value)),
+                Some(Ok(value)) => $crate::task::track_pointer(Box::into_raw(Box::new(value))),
                 Some(Err(e)) => {
                     $crate::error_handling::update_last_error(e);
                     ::std::ptr::null_mut()
@@ -202,9 +469,9 @@ macro_rules! export_task {
             }
         }
 
-        $crate::export_task!($( #[$attr] )* Task: $Task; $( $tokens )*);
+        $crate::export_task!(@[$vis] $( #[$attr] )* Task: $Task; $( $tokens )*);
     };
-    ($( #[$attr:meta] )* Task: $Task:ty; handle_destroy: $handle_destructor:ident; $( $tokens:tt )*) => {
+    (@[$vis:vis] $( #[$attr:meta] )* Task: $Task:ty; handle_destroy: $handle_destructor:ident; $( $tokens:tt )*) => {
         /// Destroy a task handle once you no longer need it, cancelling the
         /// task if it hasn't yet completed.
         ///
@@ -215,28 +482,76 @@ macro_rules! export_task {
         #[allow(dead_code)]
         #[no_mangle]
         $( #[$attr] )*
-        pub unsafe extern "C" fn $handle_destructor(handle: *mut $crate::task::TaskHandle<<$Task as $crate::Task>::Output>) {
+        $vis unsafe extern "C" fn $handle_destructor(handle: *mut $crate::task::TaskHandle<<$Task as $crate::Task>::Output>) {
             $crate::null_pointer_check!(handle);
+            if !$crate::task::release_pointer(handle) {
+                return;
+            }
             let handle = Box::from_raw(handle);
-            drop(handle);
+            // a panicking destructor mustn't unwind across the FFI boundary
+            let _ = $crate::panic::catch_panic_assert(move || {
+                drop(handle);
+                Ok(())
+            });
         }
 
-        $crate::export_task!($( #[$attr] )* Task: $Task; $( $tokens )*);
+        $crate::export_task!(@[$vis] $( #[$attr] )* Task: $Task; $( $tokens )*);
     };
-    ($( #[$attr:meta] )* Task: $Task:ty; result_destroy: $result_destroy:ident; $( $tokens:tt )*) => {
+    (@[$vis:vis] $( #[$attr:meta] )* Task: $Task:ty; result_destroy: $result_destroy:ident; $( $tokens:tt )*) => {
         /// Destroy the result of a task once you are done with it.
         #[allow(dead_code)]
         #[no_mangle]
         $( #[$attr] )*
-        pub unsafe extern "C" fn $result_destroy(result: *mut <$Task as $crate::Task>::Output) {
+        $vis unsafe extern "C" fn $result_destroy(result: *mut <$Task as $crate::Task>::Output) {
             $crate::null_pointer_check!(result);
+            if !$crate::task::release_pointer(result) {
+                return;
+            }
             let result = Box::from_raw(result);
-            drop(result);
+            // a panicking destructor mustn't unwind across the FFI boundary
+            let _ = $crate::panic::catch_panic_assert(move || {
+                drop(result);
+                Ok(())
+            });
+        }
+
+        $crate::export_task!(@[$vis] $( #[$attr] )* Task: $Task; $( $tokens )*);
+    };
+    (@[$vis:vis] $( #[$attr:meta] )* Task: $Task:ty; poll_with_code: $poll:ident; $( $tokens:tt )*) => {
+        /// Poll the task handle and retrieve the result if it's ready,
+        /// writing the error code to `out_code` (if it isn't null).
+        ///
+        /// The code is `0` when the task is still running or it finished
+        /// successfully. Otherwise `null` is returned, `LAST_ERROR` is updated,
+        /// and the code is the same as `last_error_code()`.
+        #[allow(dead_code)]
+        #[no_mangle]
+        $( #[$attr] )*
+        $vis unsafe extern "C" fn $poll(handle: *mut $crate::task::TaskHandle<<$Task as $crate::Task>::Output>, out_code: *mut ::std::os::raw::c_int) -> *mut <$Task as $crate::Task>::Output {
+            let (result, code) = match handle.as_ref() {
+                Some(handle) => match handle.poll() {
+                    Some(Ok(value)) => ($crate::task::track_pointer(Box::into_raw(Box::new(value))), 0),
+                    Some(Err(e)) => {
+                        $crate::error_handling::update_last_error(e);
+                        (::std::ptr::null_mut(), $crate::error_handling::last_error_code())
+                    }
+                    None => (::std::ptr::null_mut(), 0),
+                },
+                None => {
+                    $crate::error_handling::update_last_error($crate::NullPointer);
+                    (::std::ptr::null_mut(), $crate::error_handling::last_error_code())
+                }
+            };
+
+            if !out_code.is_null() {
+                *out_code = code;
+            }
+            result
         }
 
-        $crate::export_task!($( #[$attr] )* Task: $Task; $( $tokens )*);
+        $crate::export_task!(@[$vis] $( #[$attr] )* Task: $Task; $( $tokens )*);
     };
-    ($( #[$attr:meta] )* Task: $Task:ty; wait: $wait:ident; $( $tokens:tt )*) => {
+    (@[$vis:vis] $( #[$attr:meta] )* Task: $Task:ty; wait: $wait:ident; $( $tokens:tt )*) => {
         /// Wait for the task to finish, returning the boxed result and consuming
         /// the task handle in the process.
         ///
@@ -247,15 +562,18 @@ macro_rules! export_task {
         #[allow(dead_code)]
         #[no_mangle]
         $( #[$attr] )*
-        pub unsafe extern "C" fn $wait(handle: *mut $crate::task::TaskHandle<<$Task as $crate::Task>::Output>)
+        $vis unsafe extern "C" fn $wait(handle: *mut $crate::task::TaskHandle<<$Task as $crate::Task>::Output>)
             -> *mut <$Task as $crate::Task>::Output
         {
             $crate::null_pointer_check!(handle);
+            if !$crate::task::release_pointer(handle) {
+                return ::std::ptr::null_mut();
+            }
             let handle = Box::from_raw(handle);
             let result = handle.wait();
 
             match result {
-                Ok(value) => Box::into_raw(Box::new(value)),
+                Ok(value) => $crate::task::track_pointer(Box::into_raw(Box::new(value))),
                 Err(e) => {
                     $crate::update_last_error(e);
                     ::std::ptr::null_mut()
@@ -263,26 +581,174 @@ macro_rules! export_task {
             }
         }
 
-        $crate::export_task!($( #[$attr] )* Task: $Task; $( $tokens )*);
+        $crate::export_task!(@[$vis] $( #[$attr] )* Task: $Task; $( $tokens )*);
+    };
+    (@[$vis:vis] $( #[$attr:meta] )* Task: $Task:ty; cancel: $cancel:ident; $( $tokens:tt )*) => {
+        /// Cancel the task.
+        #[allow(dead_code)]
+        #[no_mangle]
+        $( #[$attr] )*
+        $vis unsafe extern "C" fn $cancel(handle: *mut $crate::task::TaskHandle<<$Task as $crate::Task>::Output>) {
+            $crate::null_pointer_check!(handle);
+            (&*handle).cancel();
+        }
+
+        $crate::export_task!(@[$vis] $( #[$attr] )* Task: $Task; $( $tokens )*);
+    };
+    (@[$vis:vis] $( #[$attr:meta] )* Task: $Task:ty; cancelled: $cancelled:ident; $( $tokens:tt )*) => {
+        /// Has the task already been cancelled?
+        #[allow(dead_code)]
+        #[no_mangle]
+        $( #[$attr] )*
+        $vis unsafe extern "C" fn $cancelled(handle: *mut $crate::task::TaskHandle<<$Task as $crate::Task>::Output>) -> ::std::os::raw::c_int {
+            $crate::null_pointer_check!(handle);
+            if (&*handle).cancelled() {
+                1
+            } else {
+                0
+            }
+        }
+
+        $crate::export_task!(@[$vis] $( #[$attr] )* Task: $Task; $( $tokens )*);
+    };
+    (@[$vis:vis] $( #[$attr:meta] )* Task: $Task:ty; cancelled_bool: $cancelled:ident; $( $tokens:tt )*) => {
+        /// Has the task already been cancelled?
+        #[allow(dead_code)]
+        #[no_mangle]
+        $( #[$attr] )*
+        $vis unsafe extern "C" fn $cancelled(handle: *mut $crate::task::TaskHandle<<$Task as $crate::Task>::Output>) -> $crate::FfiBool {
+            $crate::null_pointer_check!(handle);
+            (&*handle).cancelled().into()
+        }
+
+        $crate::export_task!(@[$vis] $( #[$attr] )* Task: $Task; $( $tokens )*);
+    };
+    (@[$vis:vis] $( #[$attr:meta] )* Task: $Task:ty; elapsed_ms: $elapsed_ms:ident; $( $tokens:tt )*) => {
+        /// How long the task has been running for (or took to run, if it has
+        /// finished) in milliseconds.
+        #[allow(dead_code)]
+        #[no_mangle]
+        $( #[$attr] )*
+        $vis unsafe extern "C" fn $elapsed_ms(handle: *mut $crate::task::TaskHandle<<$Task as $crate::Task>::Output>) -> u64 {
+            $crate::null_pointer_check!(handle);
+            (&*handle).elapsed().as_millis() as u64
+        }
+
+        $crate::export_task!(@[$vis] $( #[$attr] )* Task: $Task; $( $tokens )*);
+    };
+    (@[$vis:vis] $( #[$attr:meta] )* Task: $Task:ty;) => {};
+    ($( #[$attr:meta] )* vis: pub; Task: $Task:ty; $( $tokens:tt )*) => {
+        $crate::export_task!(@[pub] $( #[$attr] )* Task: $Task; $( $tokens )*);
+    };
+    ($( #[$attr:meta] )* vis: pub($( $restriction:tt )+); Task: $Task:ty; $( $tokens:tt )*) => {
+        $crate::export_task!(@[pub($( $restriction )+)] $( #[$attr] )* Task: $Task; $( $tokens )*);
+    };
+    ($( #[$attr:meta] )* Task: $Task:ty; $( $tokens:tt )*) => {
+        $crate::export_task!(@[pub] $( #[$attr] )* Task: $Task; $( $tokens )*);
+    };
+}
+
+/// Like [`export_task!()`], except the task's output is serialized as JSON
+/// and copied into a caller-provided buffer instead of being returned as a
+/// pointer.
+///
+/// The available functions are:
+///
+/// - `spawn`: Spawn a task on a background thread, returning a
+///   [`SerializedTaskHandle`]
+/// - `poll`: Copy the serialized result into a buffer if it's available
+/// - `wait`: Block until the task has finished, then copy the serialized result
+///   into a buffer
+/// - `cancel`: Cancel the background task
+/// - `cancelled`: Has the task already been cancelled?
+/// - `handle_destroy`: A destructor for the [`SerializedTaskHandle`]
+///
+/// Following the same convention as the error handling functions, `poll` and
+/// `wait` take a buffer and its length, returning the number of bytes written
+/// or the negative of the required length if the buffer is too small. They
+/// return `0` if the result isn't available yet or the task failed (in which
+/// case `LAST_ERROR` is updated). Unlike [`export_task!()`], `wait` doesn't
+/// destroy the handle so you can try again with a bigger buffer. A negative
+/// length is rejected with [`COPY_INVALID_LENGTH`].
+///
+/// Like [`export_task!()`], the handle destructor is checked when the
+/// `guard-handles` feature is enabled and the functions' visibility can be
+/// set with `vis: pub(crate);`.
+///
+/// This requires the `serde` feature.
+///
+/// [`export_task!()`]: macro.export_task.html
+/// [`SerializedTaskHandle`]: task/struct.SerializedTaskHandle.html
+/// [`COPY_INVALID_LENGTH`]: error_handling/constant.COPY_INVALID_LENGTH.html
+#[cfg(feature = "serde")]
+#[macro_export]
+macro_rules! export_serializable_task {
+    (@[$vis:vis] $( #[$attr:meta] )* Task: $Task:ty; spawn: $spawn:ident; $( $tokens:tt )*) => {
+        /// Spawn a task in the background, returning a pointer to the task
+        /// handle.
+        #[allow(dead_code)]
+        #[no_mangle]
+        $( #[$attr] )*
+        $vis unsafe extern "C" fn $spawn(task: *const $Task) -> *mut $crate::task::SerializedTaskHandle {
+            $crate::null_pointer_check!(task);
+            let task = (&*task).clone();
+            let handle = $crate::task::SerializedTaskHandle::spawn(task);
+            $crate::task::track_pointer(Box::into_raw(Box::new(handle)))
+        }
+
+        $crate::export_serializable_task!(@[$vis] $( #[$attr] )* Task: $Task; $( $tokens )*);
+    };
+    (@[$vis:vis] $( #[$attr:meta] )* Task: $Task:ty; poll: $poll:ident; $( $tokens:tt )*) => {
+        /// Copy the task's serialized result into the buffer if it's ready.
+        #[allow(dead_code)]
+        #[no_mangle]
+        $( #[$attr] )*
+        $vis unsafe extern "C" fn $poll(handle: *mut $crate::task::SerializedTaskHandle, buf: *mut u8, length: ::std::os::raw::c_int) -> ::std::os::raw::c_int {
+            $crate::null_pointer_check!(handle);
+            $crate::null_pointer_check!(buf);
+            match $crate::error_handling::buffer_from_raw(buf, length) {
+                Some(buffer) => (&*handle).poll_into(buffer),
+                None => $crate::error_handling::COPY_INVALID_LENGTH,
+            }
+        }
+
+        $crate::export_serializable_task!(@[$vis] $( #[$attr] )* Task: $Task; $( $tokens )*);
+    };
+    (@[$vis:vis] $( #[$attr:meta] )* Task: $Task:ty; wait: $wait:ident; $( $tokens:tt )*) => {
+        /// Wait for the task to finish, then copy its serialized result into
+        /// the buffer.
+        #[allow(dead_code)]
+        #[no_mangle]
+        $( #[$attr] )*
+        $vis unsafe extern "C" fn $wait(handle: *mut $crate::task::SerializedTaskHandle, buf: *mut u8, length: ::std::os::raw::c_int) -> ::std::os::raw::c_int {
+            $crate::null_pointer_check!(handle);
+            $crate::null_pointer_check!(buf);
+            match $crate::error_handling::buffer_from_raw(buf, length) {
+                Some(buffer) => (&*handle).wait_into(buffer),
+                None => $crate::error_handling::COPY_INVALID_LENGTH,
+            }
+        }
+
+        $crate::export_serializable_task!(@[$vis] $( #[$attr] )* Task: $Task; $( $tokens )*);
     };
-    ($( #[$attr:meta] )* Task: $Task:ty; cancel: $cancel:ident; $( $tokens:tt )*) => {
+    (@[$vis:vis] $( #[$attr:meta] )* Task: $Task:ty; cancel: $cancel:ident; $( $tokens:tt )*) => {
         /// Cancel the task.
         #[allow(dead_code)]
         #[no_mangle]
         $( #[$attr] )*
-        pub unsafe extern "C" fn $cancel(handle: *mut $crate::task::TaskHandle<<$Task as $crate::Task>::Output>) {
+        $vis unsafe extern "C" fn $cancel(handle: *mut $crate::task::SerializedTaskHandle) {
             $crate::null_pointer_check!(handle);
             (&*handle).cancel();
         }
 
-        $crate::export_task!($( #[$attr] )* Task: $Task; $( $tokens )*);
+        $crate::export_serializable_task!(@[$vis] $( #[$attr] )* Task: $Task; $( $tokens )*);
     };
-    ($( #[$attr:meta] )* Task: $Task:ty; cancelled: $cancelled:ident; $( $tokens:tt )*) => {
+    (@[$vis:vis] $( #[$attr:meta] )* Task: $Task:ty; cancelled: $cancelled:ident; $( $tokens:tt )*) => {
         /// Has the task already been cancelled?
         #[allow(dead_code)]
         #[no_mangle]
         $( #[$attr] )*
-        pub unsafe extern "C" fn $cancelled(handle: *mut $crate::task::TaskHandle<<$Task as $crate::Task>::Output>) -> ::std::os::raw::c_int {
+        $vis unsafe extern "C" fn $cancelled(handle: *mut $crate::task::SerializedTaskHandle) -> ::std::os::raw::c_int {
             $crate::null_pointer_check!(handle);
             if (&*handle).cancelled() {
                 1
@@ -291,9 +757,80 @@ macro_rules! export_task {
             }
         }
 
-        $crate::export_task!($( #[$attr] )* Task: $Task; $( $tokens )*);
+        $crate::export_serializable_task!(@[$vis] $( #[$attr] )* Task: $Task; $( $tokens )*);
+    };
+    (@[$vis:vis] $( #[$attr:meta] )* Task: $Task:ty; handle_destroy: $handle_destructor:ident; $( $tokens:tt )*) => {
+        /// Destroy a task handle once you no longer need it, cancelling the
+        /// task if it hasn't yet completed.
+        #[allow(dead_code)]
+        #[no_mangle]
+        $( #[$attr] )*
+        $vis unsafe extern "C" fn $handle_destructor(handle: *mut $crate::task::SerializedTaskHandle) {
+            $crate::null_pointer_check!(handle);
+            if !$crate::task::release_pointer(handle) {
+                return;
+            }
+            let handle = Box::from_raw(handle);
+            // a panicking destructor mustn't unwind across the FFI boundary
+            let _ = $crate::panic::catch_panic_assert(move || {
+                drop(handle);
+                Ok(())
+            });
+        }
+
+        $crate::export_serializable_task!(@[$vis] $( #[$attr] )* Task: $Task; $( $tokens )*);
+    };
+    (@[$vis:vis] $( #[$attr:meta] )* Task: $Task:ty;) => {};
+    ($( #[$attr:meta] )* vis: pub; Task: $Task:ty; $( $tokens:tt )*) => {
+        $crate::export_serializable_task!(@[pub] $( #[$attr] )* Task: $Task; $( $tokens )*);
+    };
+    ($( #[$attr:meta] )* vis: pub($( $restriction:tt )+); Task: $Task:ty; $( $tokens:tt )*) => {
+        $crate::export_serializable_task!(@[pub($( $restriction )+)] $( #[$attr] )* Task: $Task; $( $tokens )*);
+    };
+    ($( #[$attr:meta] )* Task: $Task:ty; $( $tokens:tt )*) => {
+        $crate::export_serializable_task!(@[pub] $( #[$attr] )* Task: $Task; $( $tokens )*);
+    };
+}
+
+/// A cancellation checkpoint, returning early with a [`Cancelled`] error if
+/// the provided [`CancellationToken`] has been triggered.
+///
+/// This is the idiomatic way to periodically check for cancellation inside
+/// [`Task::run()`], and is equivalent to `cancel_tok.is_done()?`.
+///
+/// # Examples
+///
+/// ```rust
+/// # use anyhow::Error;
+/// # use ffi_helpers::task::CancellationToken;
+/// # use ffi_helpers::Task;
+/// #[derive(Debug, Clone, Copy)]
+/// pub struct Count;
+///
+/// impl Task for Count {
+///     type Output = usize;
+///
+///     fn run(&self, cancel_tok: &CancellationToken) -> Result<usize, Error> {
+///         let mut count = 0;
+///
+///         for _ in 0..1000 {
+///             ffi_helpers::check_cancelled!(cancel_tok);
+///             count += 1;
+///         }
+///
+///         Ok(count)
+///     }
+/// }
+/// ```
+///
+/// [`Cancelled`]: task/struct.Cancelled.html
+/// [`CancellationToken`]: task/struct.CancellationToken.html
+/// [`Task::run()`]: task/trait.Task.html#tymethod.run
+#[macro_export]
+macro_rules! check_cancelled {
+    ($cancel_tok:expr) => {
+        $cancel_tok.is_done()?
     };
-    ($( #[$attr:meta] )* Task: $Task:ty;) => {};
 }
 
 /// A cancellable task which is meant to be run in a background thread.
@@ -317,122 +854,996 @@ pub trait Task: Send + Sync + Clone {
         &self,
         cancel_tok: &CancellationToken,
     ) -> Result<Self::Output, Error>;
-}
-
-/// A shareable token to let you notify other tasks they should stop what they
-/// are doing and exit early.
-#[derive(Debug, Clone)]
-pub struct CancellationToken(Arc<AtomicBool>);
-
-impl CancellationToken {
-    /// Create a new `CancellationToken`.
-    pub fn new() -> CancellationToken {
-        CancellationToken(Arc::new(AtomicBool::new(false)))
-    }
-
-    /// Has this token already been cancelled?
-    pub fn cancelled(&self) -> bool { self.0.load(Ordering::SeqCst) }
-
-    /// Cancel the token, notifying anyone else listening that they should halt
-    /// what they are doing.
-    pub fn cancel(&self) { self.0.store(true, Ordering::SeqCst); }
 
-    pub fn is_done(&self) -> Result<(), Cancelled> {
-        if self.cancelled() {
-            Err(Cancelled)
-        } else {
-            Ok(())
-        }
-    }
+    /// A human-readable name for this task, used when naming its background
+    /// thread and in log messages.
+    ///
+    /// This defaults to the task's fully-qualified type name.
+    fn name(&self) -> &str { any::type_name::<Self>() }
 }
 
-impl Default for CancellationToken {
-    fn default() -> CancellationToken { CancellationToken::new() }
+/// A [`Task`] which transforms the output of another task.
+///
+/// The function is run on the background thread after the inner task
+/// completes successfully.
+///
+/// [`Task`]: trait.Task.html
+#[derive(Clone)]
+pub struct Map<K, F> {
+    inner: K,
+    func: F,
 }
 
-/// An error to indicate a task was cancelled.
-#[derive(Debug, Clone, Copy, PartialEq, Error)]
-#[error("The task was cancelled")]
-pub struct Cancelled;
-
-/// An opaque handle to some task which is running in the background.
-pub struct TaskHandle<T> {
-    result: Receiver<Result<T, Error>>,
-    token: CancellationToken,
+impl<K, F> Map<K, F> {
+    /// Create a new `Map` which will pass the output of `inner` to `func`.
+    pub fn new(inner: K, func: F) -> Map<K, F> { Map { inner, func } }
 }
 
-impl<T> TaskHandle<T> {
-    /// Spawn a `Task` in the background, returning the a `TaskHandle` so you
-    /// can cancel it or retrieve the result later on.
-    pub fn spawn<K>(task: K) -> TaskHandle<T>
-    where
-        K: Task<Output = T> + UnwindSafe + Send + Sync + 'static,
-        T: Send + Sync + 'static,
-    {
-        let (tx, rx) = mpsc::channel();
-        let cancel_tok = CancellationToken::new();
-        let tok_2 = cancel_tok.clone();
+impl<K, F, U> Task for Map<K, F>
+where
+    K: Task,
+    F: Fn(K::Output) -> U + Send + Sync + Clone,
+    U: Send + Sync,
+{
+    type Output = U;
 
-        thread::spawn(move || {
-            error_handling::clear_last_error();
+    fn run(&self, cancel_tok: &CancellationToken) -> Result<U, Error> {
+        self.inner.run(cancel_tok).map(&self.func)
+    }
 
-            let got =
-                panic::catch_panic(move || task.run(&tok_2)).map_err(|_| {
-                    // we want to preserve panic messages and pass them back to
-                    // the main thread so we manually take
-                    // LAST_ERROR
-                    let e = error_handling::take_last_error();
-                    e.unwrap_or_else(|| anyhow::anyhow!("The task failed"))
-                });
+    fn name(&self) -> &str { self.inner.name() }
+}
 
-            tx.send(got).ok();
-        });
+/// A [`Task`] which cancels another task if it runs for longer than a
+/// particular amount of time.
+///
+/// The timeout is enforced no matter how the task is run or waited on. Like
+/// [`run_with_deadline()`], this relies on the inner task checking its
+/// [`CancellationToken`], and you'll get a [`Cancelled`] error if the timeout
+/// fires before the task finishes.
+///
+/// [`Task`]: trait.Task.html
+/// [`run_with_deadline()`]: fn.run_with_deadline.html
+/// [`CancellationToken`]: struct.CancellationToken.html
+/// [`Cancelled`]: struct.Cancelled.html
+#[derive(Debug, Clone)]
+pub struct Timeout<K> {
+    inner: K,
+    timeout: Duration,
+}
 
-        TaskHandle {
-            result: rx,
-            token: cancel_tok,
-        }
+impl<K> Timeout<K> {
+    /// Create a new `Timeout` which gives `inner` at most `timeout` to run.
+    pub fn new(inner: K, timeout: Duration) -> Timeout<K> {
+        Timeout { inner, timeout }
     }
+}
 
-    /// Check if the background task has finished.
-    ///
-    /// If the other end hangs up for whatever reason this will return an error.
-    pub fn poll(&self) -> Option<Result<T, Error>> {
-        // This looks an awful lot like the Futures API, doesn't it?
+impl<K: Task> Task for Timeout<K> {
+    type Output = K::Output;
 
-        match self.result.try_recv() {
-            Ok(value) => Some(value),
-            Err(TryRecvError::Empty) => None,
-            Err(e) => Some(Err(e.into())),
-        }
-    }
+    fn run(&self, cancel_tok: &CancellationToken) -> Result<K::Output, Error> {
+        let cancel_tok =
+            cancel_tok.with_deadline(Instant::now() + self.timeout);
 
-    /// Block the current thread until the task has finished and returned a
-    /// result.
-    pub fn wait(self) -> Result<T, Error> {
-        match self.result.recv() {
-            Ok(Ok(value)) => Ok(value),
-            Ok(Err(e)) => Err(e),
-            Err(recv_err) => Err(recv_err.into()),
+        match self.inner.run(&cancel_tok) {
+            Ok(_) if cancel_tok.interrupted() => Err(Cancelled.into()),
+            other => other,
         }
     }
 
-    /// Cancel the background task.
-    pub fn cancel(&self) { self.token.cancel(); }
-
-    /// Has this task been cancelled?
-    pub fn cancelled(&self) -> bool { self.token.cancelled() }
-}
-
-impl<T> Drop for TaskHandle<T> {
-    fn drop(&mut self) { self.token.cancel(); }
+    fn name(&self) -> &str { self.inner.name() }
 }
 
-#[cfg(test)]
-mod tests {
+/// An object-safe version of [`Task`], letting you store different kinds of
+/// task behind a `Box<dyn ErasedTask>` (e.g. in a registry of tasks which can
+/// be looked up by name).
+///
+/// This is automatically implemented for every [`Task`]. Use
+/// [`TaskHandle::spawn_erased()`] to run it in the background.
+///
+/// # Examples
+///
+/// ```rust
+/// use ffi_helpers::task::{ErasedTask, TaskHandle};
+/// # use ffi_helpers::{task::CancellationToken, Task};
+/// # use anyhow::Error;
+/// # #[derive(Clone)]
+/// # struct Answer;
+/// # impl Task for Answer {
+/// #     type Output = u32;
+/// #     fn run(&self, _: &CancellationToken) -> Result<u32, Error> { Ok(42) }
+/// # }
+///
+/// let task: Box<dyn ErasedTask> = Box::new(Answer);
+///
+/// let got = TaskHandle::spawn_erased(task).wait().unwrap();
+/// assert_eq!(got.downcast_ref::<u32>(), Some(&42));
+/// ```
+///
+/// [`Task`]: trait.Task.html
+/// [`TaskHandle::spawn_erased()`]: struct.TaskHandle.html#method.spawn_erased
+pub trait ErasedTask: Send + Sync {
+    /// Run the task, type-erasing its output.
+    fn run_erased(
+        &self,
+        cancel_tok: &CancellationToken,
+    ) -> Result<Box<dyn Any + Send>, Error>;
+}
+
+impl<K> ErasedTask for K
+where
+    K: Task,
+    K::Output: 'static,
+{
+    fn run_erased(
+        &self,
+        cancel_tok: &CancellationToken,
+    ) -> Result<Box<dyn Any + Send>, Error> {
+        self.run(cancel_tok)
+            .map(|value| Box::new(value) as Box<dyn Any + Send>)
+    }
+}
+
+/// A shareable token to let you notify other tasks they should stop what they
+/// are doing and exit early.
+#[derive(Debug, Clone)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+    generation: Arc<AtomicU64>,
+    reason: Arc<Mutex<Option<Arc<Error>>>>,
+    deadline: Option<Instant>,
+    #[cfg(all(unix, feature = "signal"))]
+    signal: Option<SignalWatch>,
+    #[cfg(unix)]
+    wakeup: Arc<Notifier>,
+}
+
+impl CancellationToken {
+    /// Create a new `CancellationToken`.
+    pub fn new() -> CancellationToken {
+        CancellationToken {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            stopped: Arc::new(AtomicBool::new(false)),
+            generation: Arc::new(AtomicU64::new(0)),
+            reason: Arc::new(Mutex::new(None)),
+            deadline: None,
+            #[cfg(all(unix, feature = "signal"))]
+            signal: None,
+            #[cfg(unix)]
+            wakeup: Arc::new(Notifier::new()),
+        }
+    }
+
+    /// Create a token which uses an existing flag to keep track of whether it
+    /// has been cancelled.
+    ///
+    /// Setting `flag` to `true` is the same as calling [`cancel()`], and
+    /// vice versa, which lets the token share a shutdown flag that is already
+    /// used elsewhere in your application. However, setting the flag
+    /// directly won't bump the [`generation()`] or make [`as_raw_fd()`]
+    /// readable.
+    ///
+    /// [`cancel()`]: #method.cancel
+    /// [`generation()`]: #method.generation
+    /// [`as_raw_fd()`]: #method.as_raw_fd
+    pub fn from_arc(flag: Arc<AtomicBool>) -> CancellationToken {
+        CancellationToken {
+            cancelled: flag,
+            ..CancellationToken::new()
+        }
+    }
+
+    /// Create a token which will report itself as cancelled once the process
+    /// receives the signal `signum` (e.g. `SIGINT` when the user hits
+    /// Ctrl-C).
+    ///
+    /// Only signals which arrive after the token is created will cancel it.
+    /// Like a deadline, receiving the signal doesn't make [`as_raw_fd()`]
+    /// readable or bump the [`generation()`].
+    ///
+    /// If the handler can't be installed (e.g. for `SIGKILL`), `LAST_ERROR`
+    /// is updated and the token can only be cancelled manually.
+    ///
+    /// This requires the `signal` feature and is only available on Unix.
+    ///
+    /// # Warning
+    ///
+    /// This installs a process-wide signal handler for `signum`. Any handler
+    /// which was already installed is still called after ours, but the
+    /// default action is not, so the signal won't terminate the process any
+    /// more. Handlers installed later (e.g. by another library) replace ours,
+    /// and ours is never uninstalled.
+    ///
+    /// [`as_raw_fd()`]: #method.as_raw_fd
+    /// [`generation()`]: #method.generation
+    #[cfg(all(unix, feature = "signal"))]
+    pub fn on_signal(signum: c_int) -> CancellationToken {
+        let mut cancel_tok = CancellationToken::new();
+
+        match SignalWatch::new(signum) {
+            Ok(watch) => cancel_tok.signal = Some(watch),
+            Err(e) => error_handling::update_last_error(e),
+        }
+
+        cancel_tok
+    }
+
+    /// Create a token which is cancelled whenever this one is, and which will
+    /// also report itself as cancelled once the `deadline` has passed.
+    ///
+    /// Reaching the deadline only affects the new token, the original token
+    /// (and anyone else sharing it) won't be cancelled.
+    pub fn with_deadline(&self, deadline: Instant) -> CancellationToken {
+        let deadline = match self.deadline {
+            Some(existing) if existing < deadline => existing,
+            _ => deadline,
+        };
+
+        CancellationToken {
+            cancelled: Arc::clone(&self.cancelled),
+            stopped: Arc::clone(&self.stopped),
+            generation: Arc::clone(&self.generation),
+            reason: Arc::clone(&self.reason),
+            deadline: Some(deadline),
+            #[cfg(all(unix, feature = "signal"))]
+            signal: self.signal,
+            #[cfg(unix)]
+            wakeup: Arc::clone(&self.wakeup),
+        }
+    }
+
+    /// Has this token already been cancelled?
+    ///
+    /// This is also `true` after [`finish_early()`], so anything else using
+    /// the token will stop what it's doing.
+    ///
+    /// [`finish_early()`]: #method.finish_early
+    pub fn cancelled(&self) -> bool {
+        self.interrupted() || self.finished_early()
+    }
+
+    /// Was the token cancelled by someone else, did its deadline pass, or
+    /// did the signal it is watching for arrive?
+    fn interrupted(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+            || self.deadline.is_some_and(|d| Instant::now() >= d)
+            || self.signal_received()
+    }
+
+    #[cfg(all(unix, feature = "signal"))]
+    fn signal_received(&self) -> bool {
+        self.signal.is_some_and(|watch| watch.fired())
+    }
+
+    #[cfg(not(all(unix, feature = "signal")))]
+    fn signal_received(&self) -> bool { false }
+
+    /// Let a task say it is stopping of its own accord (e.g. because it has
+    /// decided to return partial results).
+    ///
+    /// Anything checking the token will see it as cancelled, however the
+    /// task's [`TaskHandle`] won't report the task as cancelled. Instead its
+    /// [`status()`] will be [`TaskStatus::Completed`].
+    ///
+    /// [`TaskHandle`]: struct.TaskHandle.html
+    /// [`status()`]: struct.TaskHandle.html#method.status
+    /// [`TaskStatus::Completed`]: enum.TaskStatus.html#variant.Completed
+    pub fn finish_early(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        #[cfg(unix)]
+        self.wakeup.notify();
+    }
+
+    /// Has [`finish_early()`] been called?
+    ///
+    /// [`finish_early()`]: #method.finish_early
+    pub fn finished_early(&self) -> bool { self.stopped.load(Ordering::SeqCst) }
+
+    /// A counter which is incremented every time [`cancel()`] or
+    /// [`finish_early()`] is called.
+    ///
+    /// This only tracks those two methods. Passing a deadline, receiving the
+    /// signal from [`on_signal()`], or setting a flag shared with
+    /// [`from_arc()`] directly will cancel the token without bumping the
+    /// generation, so it is not a substitute for checking [`cancelled()`].
+    ///
+    /// [`cancel()`]: #method.cancel
+    /// [`finish_early()`]: #method.finish_early
+    /// [`on_signal()`]: #method.on_signal
+    /// [`from_arc()`]: #method.from_arc
+    /// [`cancelled()`]: #method.cancelled
+    pub fn generation(&self) -> u64 { self.generation.load(Ordering::Acquire) }
+
+    /// Cancel the token, notifying anyone else listening that they should halt
+    /// what they are doing.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        #[cfg(unix)]
+        self.wakeup.notify();
+    }
+
+    /// Cancel the token because of an error, letting anyone checking
+    /// [`is_done()`] see the root cause.
+    ///
+    /// Only the first error is kept if this is called multiple times.
+    ///
+    /// [`is_done()`]: #method.is_done
+    pub fn cancel_with_error(&self, err: Error) {
+        lock(&self.reason).get_or_insert_with(|| Arc::new(err));
+        self.cancel();
+    }
+
+    /// Get a file descriptor which becomes readable when the token is
+    /// cancelled.
+    ///
+    /// This lets tasks which spend their time blocked on I/O `select()` or
+    /// `poll()` on both their own file descriptors and this one, so they can
+    /// respond to cancellation promptly. Passing a deadline doesn't make the
+    /// file descriptor readable, you'll need to use an appropriate timeout for
+    /// that.
+    ///
+    /// The file descriptor is owned by the token, so don't close it or read
+    /// from it. Returns `-1` if the file descriptor couldn't be created.
+    #[cfg(unix)]
+    pub fn as_raw_fd(&self) -> RawFd { self.wakeup.fd() }
+
+    /// Get a [`Cancelled`] error if this token has been cancelled.
+    ///
+    /// Only cancellation by someone else (or a deadline or signal) counts. A
+    /// task which called [`finish_early()`] is still allowed to finish, so
+    /// this returns `Ok(())` for it.
+    ///
+    /// If the token was cancelled using [`cancel_with_error()`], the
+    /// [`Cancelled`] is attached as context to the original error so its
+    /// message is preserved. Either way, the error can be downcast to
+    /// [`Cancelled`].
+    ///
+    /// See also the [`check_cancelled!()`] macro.
+    ///
+    /// [`Cancelled`]: struct.Cancelled.html
+    /// [`cancel_with_error()`]: #method.cancel_with_error
+    /// [`check_cancelled!()`]: ../macro.check_cancelled.html
+    /// [`finish_early()`]: #method.finish_early
+    pub fn is_done(&self) -> Result<(), Error> {
+        if !self.interrupted() {
+            return Ok(());
+        }
+
+        match *lock(&self.reason) {
+            Some(ref reason) => {
+                let reason = SharedError(Arc::clone(reason));
+                Err(Error::new(reason).context(Cancelled))
+            },
+            None => Err(Cancelled.into()),
+        }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> CancellationToken { CancellationToken::new() }
+}
+
+/// Run a closure in the background, without needing to define a [`Task`].
+///
+/// The closure is only ever called once, so unlike a [`Task`] it doesn't need
+/// to be `Clone`.
+///
+/// [`Task`]: trait.Task.html
+pub fn spawn_fn<F, T>(func: F) -> TaskHandle<T>
+where
+    F: FnOnce(&CancellationToken) -> Result<T, Error> + Send + 'static,
+    T: Send + 'static,
+{
+    // the closure is moved to the background thread and dropped when it
+    // finishes, so nobody can observe it in a broken state after a panic
+    let func = AssertUnwindSafe(func);
+
+    TaskHandle::spawn_with(move |cancel_tok| {
+        // move the whole wrapper, not just the closure inside it
+        let AssertUnwindSafe(func) = { func };
+        func(cancel_tok)
+    })
+}
+
+/// Run a `Task` to completion on the current thread, cancelling it if it
+/// takes longer than `deadline`.
+///
+/// Because cancellation is cooperative, this relies on the task periodically
+/// checking its [`CancellationToken`]. If the deadline fires before the task
+/// finishes successfully you'll get a [`Cancelled`] error.
+///
+/// This avoids the overhead of spawning a background thread for quick
+/// operations which are already cancellation-aware.
+///
+/// [`CancellationToken`]: struct.CancellationToken.html
+/// [`Cancelled`]: struct.Cancelled.html
+pub fn run_with_deadline<K: Task>(
+    task: &K,
+    deadline: Duration,
+) -> Result<K::Output, Error> {
+    let cancel_tok =
+        CancellationToken::new().with_deadline(Instant::now() + deadline);
+
+    match task.run(&cancel_tok) {
+        Ok(_) if cancel_tok.interrupted() => Err(Cancelled.into()),
+        other => other,
+    }
+}
+
+/// An error which can be handed out to several tasks at once.
+#[derive(Debug)]
+struct SharedError(Arc<Error>);
+
+impl std::fmt::Display for SharedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl std::error::Error for SharedError {}
+
+/// An error to indicate a task was cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Error)]
+#[error("The task was cancelled")]
+pub struct Cancelled;
+
+/// Passed to the error hooks when the last [`TaskHandle`] for a task is
+/// dropped while it is still running, even though it had already been
+/// cancelled.
+///
+/// This usually means the task never checks its [`CancellationToken`], and it
+/// will show up in [`leaked_task_count()`].
+///
+/// [`TaskHandle`]: struct.TaskHandle.html
+/// [`CancellationToken`]: struct.CancellationToken.html
+/// [`leaked_task_count()`]: fn.leaked_task_count.html
+#[derive(Debug, Clone, Copy, PartialEq, Error)]
+#[error("A cancelled task was still running when its last handle was dropped")]
+pub struct LeakedTask;
+
+/// The state of a background task, as reported by [`TaskHandle::status()`].
+///
+/// [`TaskHandle::status()`]: struct.TaskHandle.html#method.status
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskStatus {
+    /// The task is still running.
+    Running,
+    /// The task finished by itself, either because it ran to completion or
+    /// because it called [`CancellationToken::finish_early()`].
+    ///
+    /// [`CancellationToken::finish_early()`]: struct.CancellationToken.html#method.finish_early
+    Completed,
+    /// The task finished after being cancelled by the caller (or its
+    /// deadline passing).
+    Cancelled,
+}
+
+/// An opaque handle to some task which is running in the background.
+///
+/// A `TaskHandle` can be cloned so several observers can monitor the same task
+/// (e.g. to cancel it or check whether it has finished), however the task's
+/// result can only be retrieved once. The task is cancelled when the last
+/// handle is dropped.
+pub struct TaskHandle<T> {
+    inner: Arc<Inner<T>>,
+}
+
+struct Inner<T> {
+    result: Mutex<Receiver<Result<T, Error>>>,
+    slot: Mutex<Slot<T>>,
+    token: CancellationToken,
+    started: Instant,
+    finished: Arc<OnceLock<Instant>>,
+    panicked: Arc<OnceLock<bool>>,
+    lifecycle: Arc<AtomicU8>,
+    #[cfg(unix)]
+    completed: Arc<Notifier>,
+}
+
+/// The result of a task, as seen by its handles.
+enum Slot<T> {
+    Pending,
+    Ready(Result<T, Error>),
+    Taken,
+}
+
+impl<T> TaskHandle<T> {
+    /// Spawn a `Task` in the background, returning the a `TaskHandle` so you
+    /// can cancel it or retrieve the result later on.
+    pub fn spawn<K>(task: K) -> TaskHandle<T>
+    where
+        K: Task<Output = T> + UnwindSafe + Send + Sync + 'static,
+        T: Send + Sync + 'static,
+    {
+        let name = task.name().to_string();
+
+        TaskHandle::spawn_named(&name, move |cancel_tok| {
+            run_counted(&task, cancel_tok)
+        })
+    }
+
+    /// Spawn a `Task` on a background thread with a particular stack size (in
+    /// bytes).
+    ///
+    /// This is useful for tasks which recurse deeply or keep large buffers on
+    /// the stack, and would otherwise overflow the default stack.
+    pub fn spawn_with_stack_size<K>(task: K, stack_size: usize) -> TaskHandle<T>
+    where
+        K: Task<Output = T> + UnwindSafe + Send + Sync + 'static,
+        T: Send + Sync + 'static,
+    {
+        let name = task.name().to_string();
+
+        TaskHandle::spawn_on_thread(
+            move |cancel_tok| run_counted(&task, cancel_tok),
+            Some(&name),
+            Some(stack_size),
+        )
+    }
+
+    fn spawn_with<F>(run: F) -> TaskHandle<T>
+    where
+        F: FnOnce(&CancellationToken) -> Result<T, Error>
+            + UnwindSafe
+            + Send
+            + 'static,
+        T: Send + 'static,
+    {
+        TaskHandle::spawn_on_thread(run, None, default_stack_size())
+    }
+
+    /// Like `spawn_with()`, but the background thread is given a name (e.g.
+    /// from [`Task::name()`]) so it can be identified in a debugger.
+    ///
+    /// [`Task::name()`]: trait.Task.html#method.name
+    fn spawn_named<F>(name: &str, run: F) -> TaskHandle<T>
+    where
+        F: FnOnce(&CancellationToken) -> Result<T, Error>
+            + UnwindSafe
+            + Send
+            + 'static,
+        T: Send + 'static,
+    {
+        TaskHandle::spawn_on_thread(run, Some(name), default_stack_size())
+    }
+
+    /// Run a `Task` to completion on the current thread, returning a
+    /// `TaskHandle` whose result is available immediately.
+    ///
+    /// This is mainly useful for debugging, because errors and panics happen
+    /// on the caller's thread instead of in the background. Panics are still
+    /// caught and turned into errors.
+    pub fn run_inline<K>(task: K) -> TaskHandle<T>
+    where
+        K: Task<Output = T> + UnwindSafe + Send + 'static,
+        T: Send + 'static,
+    {
+        #[cfg(feature = "log")]
+        log::debug!("Running a {} on the current thread", task.name());
+
+        TaskHandle::start(
+            move |cancel_tok| run_counted(&task, cancel_tok),
+            None,
+        )
+    }
+
+    fn spawn_on_thread<F>(
+        run: F,
+        name: Option<&str>,
+        stack_size: Option<usize>,
+    ) -> TaskHandle<T>
+    where
+        F: FnOnce(&CancellationToken) -> Result<T, Error>
+            + UnwindSafe
+            + Send
+            + 'static,
+        T: Send + 'static,
+    {
+        TaskHandle::start(run, Some(thread_builder(name, stack_size)))
+    }
+
+    /// Start running a task on a new thread, or on the current thread if no
+    /// `thread::Builder` is provided.
+    fn start<F>(run: F, builder: Option<thread::Builder>) -> TaskHandle<T>
+    where
+        F: FnOnce(&CancellationToken) -> Result<T, Error>
+            + UnwindSafe
+            + Send
+            + 'static,
+        T: Send + 'static,
+    {
+        let (handle, deferred) = TaskHandle::deferred(run);
+
+        match builder {
+            Some(builder) => deferred.spawn(builder),
+            None => deferred.run_here(),
+        }
+
+        handle
+    }
+
+    /// Create the `TaskHandle` for a task without starting it, leaving the
+    /// caller to decide when (and whether) the task is run.
+    pub(crate) fn deferred<F>(run: F) -> (TaskHandle<T>, Deferred)
+    where
+        F: FnOnce(&CancellationToken) -> Result<T, Error>
+            + UnwindSafe
+            + Send
+            + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let cancel_tok = CancellationToken::new();
+        let tok_2 = cancel_tok.clone();
+        #[cfg(unix)]
+        let completed = Arc::new(Notifier::new());
+        #[cfg(unix)]
+        let completed_2 = Arc::clone(&completed);
+        let started = Instant::now();
+        let finished = Arc::new(OnceLock::new());
+        let finished_2 = Arc::clone(&finished);
+        let panicked = Arc::new(OnceLock::new());
+        let panicked_2 = Arc::clone(&panicked);
+        let lifecycle = Arc::new(AtomicU8::new(RUNNING));
+        let lifecycle_2 = Arc::clone(&lifecycle);
+
+        let background = move |should_run: bool| {
+            let got = if should_run {
+                ACTIVE_TASKS.fetch_add(1, Ordering::SeqCst);
+                // the error is sent to the handle as-is, it'll go through
+                // update_last_error() when someone retrieves it
+                panic::capture_panic(move || run(&tok_2))
+            } else {
+                Err(Error::from(Cancelled))
+            };
+
+            // update the bookkeeping before sending so anyone waiting on the
+            // result will never see this task as still running
+            let is_panic = matches!(got, Err(ref e) if e.is::<panic::Panic>());
+            panicked_2.set(is_panic).ok();
+            finished_2.set(Instant::now()).ok();
+            if should_run {
+                ACTIVE_TASKS.fetch_sub(1, Ordering::SeqCst);
+            }
+            if lifecycle_2.swap(FINISHED, Ordering::SeqCst) == DETACHED {
+                LEAKED_TASKS.fetch_sub(1, Ordering::SeqCst);
+            }
+            tx.send(got).ok();
+            #[cfg(unix)]
+            completed_2.notify();
+        };
+        let inner = Arc::new(Inner {
+            result: Mutex::new(rx),
+            slot: Mutex::new(Slot::Pending),
+            token: cancel_tok,
+            started,
+            finished,
+            panicked,
+            lifecycle,
+            #[cfg(unix)]
+            completed,
+        });
+        let weak = Arc::downgrade(&inner);
+        lock(&LIVE_TASKS).push(weak);
+
+        let deferred = Deferred {
+            run: Box::new(background),
+        };
+
+        (TaskHandle { inner }, deferred)
+    }
+
+    /// Check if the background task has finished.
+    ///
+    /// If the other end hangs up for whatever reason, or the result has
+    /// already been retrieved by another handle, this will return an error.
+    pub fn poll(&self) -> Option<Result<T, Error>> {
+        // This looks an awful lot like the Futures API, doesn't it?
+
+        let mut slot = self.inner.slot();
+        self.inner.try_receive(&mut slot);
+
+        match *slot {
+            Slot::Pending => None,
+            _ => Some(take_result(&mut slot)),
+        }
+    }
+
+    /// Like [`TaskHandle::poll()`], but shaped so errors can be propagated
+    /// using `?`, with `Ok(None)` meaning the task is still running.
+    ///
+    /// [`TaskHandle::poll()`]: #method.poll
+    pub fn poll_result(&self) -> Result<Option<T>, Error> {
+        self.poll().transpose()
+    }
+
+    /// Has the background task finished?
+    ///
+    /// Unlike [`TaskHandle::poll()`] this doesn't retrieve the result, so any
+    /// number of handles can use it to observe the task's completion.
+    ///
+    /// [`TaskHandle::poll()`]: #method.poll
+    pub fn is_finished(&self) -> bool {
+        let mut slot = self.inner.slot();
+        self.inner.try_receive(&mut slot);
+
+        !matches!(*slot, Slot::Pending)
+    }
+
+    /// Block the current thread until the task has finished and returned a
+    /// result.
+    pub fn wait(self) -> Result<T, Error> {
+        let result = lock(&self.inner.result);
+        let pending = matches!(*self.inner.slot(), Slot::Pending);
+
+        if pending {
+            // nobody else can fill the slot while we're holding the receiver,
+            // so it's okay to block without holding the lock
+            let got = match result.recv() {
+                Ok(got) => got,
+                Err(recv_err) => Err(recv_err.into()),
+            };
+            *self.inner.slot() = Slot::Ready(got);
+        }
+
+        take_result(&mut self.inner.slot())
+    }
+
+    /// Retrieve the task's result if it has finished, otherwise hand the
+    /// handle back so you can try again later.
+    ///
+    /// This is a consuming version of [`TaskHandle::poll()`] which never
+    /// blocks.
+    ///
+    /// [`TaskHandle::poll()`]: #method.poll
+    pub fn try_wait(self) -> Result<Result<T, Error>, TaskHandle<T>> {
+        match self.poll() {
+            Some(result) => Ok(result),
+            None => Err(self),
+        }
+    }
+
+    /// Block until the task has finished, type-erasing its result.
+    ///
+    /// This is useful when you want to keep the results of several different
+    /// kinds of task in the same container.
+    pub fn wait_boxed(self) -> Result<Box<dyn Any + Send>, Error>
+    where
+        T: Send + 'static,
+    {
+        self.wait()
+            .map(|value| Box::new(value) as Box<dyn Any + Send>)
+    }
+
+    /// Cancel the background task.
+    pub fn cancel(&self) { self.inner.token.cancel(); }
+
+    /// Has this task been cancelled?
+    ///
+    /// A task which stopped itself using
+    /// [`CancellationToken::finish_early()`] isn't considered cancelled.
+    ///
+    /// [`CancellationToken::finish_early()`]: struct.CancellationToken.html#method.finish_early
+    pub fn cancelled(&self) -> bool { self.inner.token.interrupted() }
+
+    /// Did the task panic?
+    ///
+    /// This is `None` while the task is still running, letting you tell
+    /// panics apart from normal errors without needing to downcast the
+    /// result to a [`Panic`]. It keeps working after the result has been
+    /// retrieved.
+    ///
+    /// [`Panic`]: ../panic/struct.Panic.html
+    pub fn last_outcome_was_panic(&self) -> Option<bool> {
+        self.inner.panicked.get().copied()
+    }
+
+    /// Is the task still running, and if not, how did it finish?
+    pub fn status(&self) -> TaskStatus {
+        let token = &self.inner.token;
+
+        if !self.is_finished() {
+            TaskStatus::Running
+        } else if token.interrupted() && !token.finished_early() {
+            TaskStatus::Cancelled
+        } else {
+            TaskStatus::Completed
+        }
+    }
+
+    /// How long the task has been running for, or how long it took if it has
+    /// already finished.
+    pub fn elapsed(&self) -> Duration {
+        match self.inner.finished.get() {
+            Some(&finished) => finished - self.inner.started,
+            None => self.inner.started.elapsed(),
+        }
+    }
+
+    /// Get a file descriptor which will become readable once the task has
+    /// finished, so it can be waited on alongside others using `poll()`,
+    /// `epoll`, and friends.
+    ///
+    /// The file descriptor is owned by the task and stays valid until the last
+    /// `TaskHandle` is dropped, so don't close it or read from it. Returns
+    /// `-1` if the file descriptor couldn't be created.
+    #[cfg(unix)]
+    pub fn completion_fd(&self) -> RawFd { self.inner.completed.fd() }
+}
+
+impl TaskHandle<Box<dyn Any + Send>> {
+    /// Spawn a type-erased task in the background.
+    ///
+    /// See [`ErasedTask`] for more.
+    ///
+    /// [`ErasedTask`]: trait.ErasedTask.html
+    pub fn spawn_erased(
+        task: Box<dyn ErasedTask>,
+    ) -> TaskHandle<Box<dyn Any + Send>> {
+        // the task is moved to the background thread and dropped when it
+        // finishes, so nobody can observe it in a broken state after a panic
+        let task = AssertUnwindSafe(task);
+
+        TaskHandle::spawn_with(move |cancel_tok| task.run_erased(cancel_tok))
+    }
+}
+
+impl<T> Clone for TaskHandle<T> {
+    fn clone(&self) -> TaskHandle<T> {
+        TaskHandle {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<T> Inner<T> {
+    fn slot(&self) -> MutexGuard<'_, Slot<T>> { lock(&self.slot) }
+
+    /// Move the result into the slot if it's available, without blocking.
+    fn try_receive(&self, slot: &mut Slot<T>) {
+        if !matches!(*slot, Slot::Pending) {
+            return;
+        }
+
+        // if someone else is holding the receiver they're blocked in wait()
+        // and will fill the slot themselves
+        let result = match self.result.try_lock() {
+            Ok(result) => result,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => return,
+        };
+
+        match result.try_recv() {
+            Ok(got) => *slot = Slot::Ready(got),
+            Err(TryRecvError::Empty) => {},
+            Err(e) => *slot = Slot::Ready(Err(e.into())),
+        }
+    }
+}
+
+impl<T> Cancel for Inner<T> {
+    fn cancel(&self) { self.token.cancel(); }
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        let already_cancelled = self.token.interrupted();
+        self.token.cancel();
+        lock(&LIVE_TASKS).retain(|task| task.strong_count() > 0);
+
+        if !already_cancelled {
+            // a cooperative task will notice the cancellation and stop soon
+            return;
+        }
+
+        // count the task first, so the background thread never sees it as
+        // detached before it has been counted
+        LEAKED_TASKS.fetch_add(1, Ordering::SeqCst);
+        let detached = self
+            .lifecycle
+            .compare_exchange(
+                RUNNING,
+                DETACHED,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .is_ok();
+
+        if detached {
+            error_handling::run_error_hooks(&Error::from(LeakedTask));
+        } else {
+            LEAKED_TASKS.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// A task which already has a [`TaskHandle`] but hasn't started running yet
+/// (see `TaskHandle::deferred()`).
+///
+/// [`TaskHandle`]: struct.TaskHandle.html
+pub(crate) struct Deferred {
+    run: Box<dyn FnOnce(bool) + Send>,
+}
+
+impl Deferred {
+    /// Run the task on a new thread.
+    pub(crate) fn spawn(self, builder: thread::Builder) {
+        builder
+            .spawn(move || {
+                error_handling::clear_last_error();
+                (self.run)(true);
+            })
+            .expect("Unable to spawn the task's background thread");
+    }
+
+    /// Run the task to completion on the current thread.
+    pub(crate) fn run_here(self) { (self.run)(true) }
+
+    /// Give the task's handle a [`Cancelled`] error without ever running
+    /// the task.
+    ///
+    /// [`Cancelled`]: struct.Cancelled.html
+    pub(crate) fn cancel(self) { (self.run)(false) }
+}
+
+impl std::fmt::Debug for Deferred {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Deferred").finish_non_exhaustive()
+    }
+}
+
+fn thread_builder(
+    name: Option<&str>,
+    stack_size: Option<usize>,
+) -> thread::Builder {
+    let mut builder = thread::Builder::new();
+    if let Some(name) = name {
+        builder = builder.name(name.to_string());
+    }
+    if let Some(stack_size) = stack_size {
+        builder = builder.stack_size(stack_size);
+    }
+
+    builder
+}
+
+fn take_result<T>(slot: &mut Slot<T>) -> Result<T, Error> {
+    match mem::replace(slot, Slot::Taken) {
+        Slot::Ready(result) => result,
+        Slot::Taken => Err(anyhow::anyhow!(
+            "The task's result has already been retrieved"
+        )),
+        Slot::Pending => unreachable!("The task hasn't finished yet"),
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
     use super::*;
+    #[cfg(not(feature = "no-catch-panic"))]
     use crate::panic::Panic;
-    use std::time::Duration;
+    #[cfg(unix)]
+    use libc::c_int;
+
+    /// Wait up to `timeout_ms` for the file descriptor to become readable.
+    #[cfg(unix)]
+    fn readable(fd: RawFd, timeout_ms: c_int) -> bool {
+        let mut pfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ret = unsafe { libc::poll(&mut pfd, 1, timeout_ms) };
+        ret == 1 && pfd.revents & libc::POLLIN != 0
+    }
 
     #[derive(Debug, Clone, Copy)]
     pub struct Spin;
@@ -473,7 +1884,130 @@ mod tests {
         let got = handle.wait().unwrap();
         // the task should have spun 9-12 times (depending on the OS's
         // scheduler)
-        assert!(9 <= got && got <= 12);
+        assert!((9..=12).contains(&got));
+    }
+
+    #[test]
+    fn try_wait_hands_back_running_tasks() {
+        let handle = TaskHandle::spawn(Spin);
+
+        let handle = handle.try_wait().err().unwrap();
+        handle.cancel();
+        while !handle.is_finished() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let got = handle.try_wait().ok().unwrap();
+        assert!(got.is_ok());
+    }
+
+    #[test]
+    fn cloned_handles_can_all_observe_completion() {
+        let first = TaskHandle::spawn(Spin);
+        let second = first.clone();
+
+        assert!(!first.is_finished());
+        assert!(!second.is_finished());
+
+        second.cancel();
+        assert!(first.cancelled());
+
+        while !first.is_finished() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(second.is_finished());
+
+        assert!(first.poll().unwrap().is_ok());
+        assert!(second.is_finished());
+        assert!(second.poll().unwrap().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn completion_fd_becomes_readable_when_the_task_finishes() {
+        let handle = TaskHandle::spawn(Spin);
+        let fd = handle.completion_fd();
+        assert!(fd >= 0);
+        assert!(!readable(fd, 20));
+
+        handle.cancel();
+
+        assert!(readable(fd, 5000));
+        assert!(handle.poll().unwrap().is_ok());
+    }
+
+    #[test]
+    fn spawn_a_task_with_a_bigger_stack() {
+        #[derive(Debug, Clone, Copy)]
+        struct BigStack;
+
+        impl Task for BigStack {
+            type Output = u8;
+
+            fn run(&self, _: &CancellationToken) -> Result<u8, Error> {
+                // much bigger than the usual 2 MiB stack
+                let buffer = [1_u8; 8 * 1024 * 1024];
+                let buffer = std::hint::black_box(buffer);
+                Ok(buffer[buffer.len() - 1])
+            }
+        }
+
+        let handle =
+            TaskHandle::spawn_with_stack_size(BigStack, 32 * 1024 * 1024);
+
+        assert_eq!(handle.wait().unwrap(), 1);
+    }
+
+    #[test]
+    fn track_how_long_a_task_has_been_running() {
+        let handle = TaskHandle::spawn(Spin);
+        thread::sleep(Duration::from_millis(50));
+
+        let running = handle.elapsed();
+        assert!(running >= Duration::from_millis(50));
+        assert!(running < Duration::from_secs(5));
+
+        handle.cancel();
+        while !handle.is_finished() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // once the task finishes, the elapsed time stops increasing
+        let took = handle.elapsed();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(handle.elapsed(), took);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cancelling_wakes_up_anyone_polling_the_token() {
+        let cancel_tok = CancellationToken::new();
+        let fd = cancel_tok.as_raw_fd();
+        assert!(fd >= 0);
+        assert!(!readable(fd, 0));
+
+        let tok_2 = cancel_tok.clone();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            tok_2.cancel();
+        });
+
+        assert!(readable(fd, 5000));
+        assert!(cancel_tok.cancelled());
+        canceller.join().unwrap();
+    }
+
+    #[test]
+    fn dropping_the_last_handle_cancels_the_task() {
+        let first = TaskHandle::spawn(Spin);
+        let second = first.clone();
+        let cancel_tok = first.inner.token.clone();
+
+        drop(first);
+        assert!(!cancel_tok.cancelled());
+
+        drop(second);
+        assert!(cancel_tok.cancelled());
     }
 
     export_task! {
@@ -481,10 +2015,12 @@ mod tests {
         spawn: spin_spawn;
         wait: spin_wait;
         poll: spin_poll;
+        poll_with_code: spin_poll_with_code;
         cancel: spin_cancel;
         cancelled: spin_cancelled;
         handle_destroy: spin_handle_destroy;
         result_destroy: spin_result_destroy;
+        elapsed_ms: spin_elapsed_ms;
     }
 
     #[test]
@@ -511,6 +2047,9 @@ mod tests {
                 "There shouldn't have been any errors"
             );
 
+            thread::sleep(Duration::from_millis(20));
+            assert!(spin_elapsed_ms(handle) >= 20);
+
             // tell the task to stop spinning
             spin_cancel(handle);
 
@@ -526,10 +2065,419 @@ mod tests {
         }
     }
 
+    pub struct Exploding;
+
+    impl Drop for Exploding {
+        fn drop(&mut self) {
+            panic!("Kaboom");
+        }
+    }
+
+    #[derive(Copy, Clone)]
+    pub struct Explode;
+
+    impl Task for Explode {
+        type Output = Exploding;
+
+        fn run(&self, _: &CancellationToken) -> Result<Exploding, Error> {
+            Ok(Exploding)
+        }
+    }
+
+    export_task! {
+        Task: Explode;
+        spawn: explode_spawn;
+        wait: explode_wait;
+        result_destroy: explode_result_destroy;
+    }
+
+    #[cfg(not(feature = "no-catch-panic"))]
+    #[test]
+    fn panicking_destructors_are_caught() {
+        use crate::error_handling::*;
+
+        unsafe {
+            let handle = explode_spawn(&Explode);
+            let got = explode_wait(handle);
+            assert!(!got.is_null());
+
+            clear_last_error();
+            explode_result_destroy(got);
+        }
+
+        let err = take_last_error().unwrap();
+        assert_eq!(err.downcast_ref::<Panic>().unwrap().message, "Kaboom");
+    }
+
+    #[derive(Copy, Clone)]
+    pub struct Answer;
+
+    impl Task for Answer {
+        type Output = u32;
+
+        fn run(&self, _: &CancellationToken) -> Result<u32, Error> { Ok(42) }
+    }
+
+    export_task! {
+        Task: Answer;
+        mode: synchronous;
+        spawn: answer_spawn;
+        poll: answer_poll;
+        handle_destroy: answer_handle_destroy;
+        result_destroy: answer_result_destroy;
+    }
+
+    #[test]
+    fn synchronous_tasks_are_ready_straight_away() {
+        unsafe {
+            let handle = answer_spawn(&Answer);
+            let got = answer_poll(handle);
+
+            assert!(!got.is_null());
+            assert_eq!(*got, 42);
+            answer_result_destroy(got);
+            answer_handle_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn poll_reports_the_error_code_inline() {
+        use crate::error_handling::*;
+
+        let finished = TaskHandle::spawn(Spin);
+        finished.cancel();
+        let failed: TaskHandle<usize> =
+            spawn_fn(|_| Err(anyhow::anyhow!("Oops")));
+        while !finished.is_finished() || !failed.is_finished() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let finished = track_pointer(Box::into_raw(Box::new(finished)));
+        let failed = track_pointer(Box::into_raw(Box::new(failed)));
+        let mut code = 42;
+
+        unsafe {
+            let got = spin_poll_with_code(finished, &mut code);
+            assert!(!got.is_null());
+            assert_eq!(code, 0);
+            spin_result_destroy(got);
+
+            let got = spin_poll_with_code(failed, &mut code);
+            assert!(got.is_null());
+            assert_eq!(code, DEFAULT_ERROR_CODE);
+            assert_eq!(error_message().unwrap(), "Oops");
+            clear_last_error();
+
+            spin_handle_destroy(finished);
+            spin_handle_destroy(failed);
+        }
+    }
+
+    mod restricted {
+        use super::Spin;
+
+        export_task! {
+            vis: pub(crate);
+            Task: Spin;
+            spawn: restricted_spin_spawn;
+            cancel: restricted_spin_cancel;
+            cancelled_bool: restricted_spin_cancelled;
+            wait: restricted_spin_wait;
+            result_destroy: restricted_spin_result_destroy;
+        }
+    }
+
+    #[test]
+    fn generate_functions_with_a_custom_visibility() {
+        use self::restricted::*;
+        use crate::FfiBool;
+
+        unsafe {
+            let handle = restricted_spin_spawn(&Spin);
+            assert_eq!(restricted_spin_cancelled(handle), FfiBool::False);
+            restricted_spin_cancel(handle);
+            assert_eq!(restricted_spin_cancelled(handle), FfiBool::True);
+
+            let got = restricted_spin_wait(handle);
+            assert!(!got.is_null());
+            restricted_spin_result_destroy(got);
+        }
+    }
+
+    #[cfg(feature = "guard-handles")]
+    #[test]
+    fn destroying_a_result_twice_is_detected() {
+        use crate::error_handling::*;
+
+        unsafe {
+            let handle = spin_spawn(&Spin);
+            spin_cancel(handle);
+            let result = spin_wait(handle);
+            assert!(!result.is_null());
+
+            clear_last_error();
+            spin_result_destroy(result);
+            assert!(!last_error_is::<UnknownPointer>());
+
+            spin_result_destroy(result);
+            assert!(last_error_is::<UnknownPointer>());
+            clear_last_error();
+        }
+    }
+
+    #[test]
+    fn spawn_a_closure() {
+        let numbers = vec![1, 2, 3, 4];
+
+        let handle = spawn_fn(move |_| Ok(numbers.into_iter().sum::<i32>()));
+
+        assert_eq!(handle.wait().unwrap(), 10);
+    }
+
+    #[test]
+    fn run_a_task_inline_with_a_deadline() {
+        let err =
+            run_with_deadline(&Spin, Duration::from_millis(50)).unwrap_err();
+
+        assert!(err.downcast_ref::<Cancelled>().is_some());
+    }
+
+    #[test]
+    fn poll_for_a_result_using_the_question_mark_operator() {
+        let running = TaskHandle::spawn(Spin);
+        let finished = TaskHandle::spawn(Spin);
+        finished.cancel();
+        let failed: TaskHandle<usize> =
+            spawn_fn(|_| Err(anyhow::anyhow!("Oops")));
+        while !finished.is_finished() || !failed.is_finished() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert!(running.poll_result().unwrap().is_none());
+        assert!(finished.poll_result().unwrap().is_some());
+        assert_eq!(failed.poll_result().unwrap_err().to_string(), "Oops");
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    struct ThreadName;
+
+    impl Task for ThreadName {
+        type Output = Option<String>;
+
+        fn run(&self, _: &CancellationToken) -> Result<Self::Output, Error> {
+            Ok(thread::current().name().map(String::from))
+        }
+
+        fn name(&self) -> &str { "thread-name" }
+    }
+
+    #[test]
+    fn background_threads_are_named_after_the_task() {
+        let got = TaskHandle::spawn(ThreadName).wait().unwrap();
+
+        assert_eq!(got.as_deref(), Some("thread-name"));
+    }
+
+    #[test]
+    fn tokens_can_share_an_existing_flag() {
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let cancel_tok = CancellationToken::from_arc(Arc::clone(&shutdown));
+        assert!(!cancel_tok.cancelled());
+
+        shutdown.store(true, Ordering::SeqCst);
+        assert!(cancel_tok.cancelled());
+
+        let other = Arc::new(AtomicBool::new(false));
+        CancellationToken::from_arc(Arc::clone(&other)).cancel();
+        assert!(other.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn tasks_with_a_timeout_stop_by_themselves() {
+        let handle =
+            TaskHandle::spawn(Timeout::new(Spin, Duration::from_millis(50)));
+
+        let err = handle.wait().unwrap_err();
+
+        assert!(err.downcast_ref::<Cancelled>().is_some());
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    struct Checkpoints;
+
+    impl Task for Checkpoints {
+        type Output = ();
+
+        fn run(&self, cancel_tok: &CancellationToken) -> Result<(), Error> {
+            loop {
+                check_cancelled!(cancel_tok);
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+
+    #[test]
+    fn finishing_early_passes_checkpoints() {
+        fn partial(cancel_tok: &CancellationToken) -> Result<u32, Error> {
+            cancel_tok.finish_early();
+            check_cancelled!(cancel_tok);
+            Ok(42)
+        }
+
+        let handle = spawn_fn(partial);
+
+        assert_eq!(handle.wait().unwrap(), 42);
+    }
+
+    #[test]
+    fn cancelling_with_an_error_passes_it_to_children() {
+        let parent = CancellationToken::new();
+        let child = parent.clone();
+        assert!(child.is_done().is_ok());
+
+        parent.cancel_with_error(anyhow::anyhow!("The parent failed"));
+
+        let err = child.is_done().unwrap_err();
+        assert!(err.is::<Cancelled>());
+        assert_eq!(
+            format!("{:#}", err),
+            "The task was cancelled: The parent failed"
+        );
+    }
+
+    #[test]
+    fn each_cancel_bumps_the_generation_once() {
+        let token = CancellationToken::new();
+        let child = token.clone();
+        assert_eq!(child.generation(), 0);
+
+        token.cancel();
+        assert_eq!(child.generation(), 1);
+
+        token.cancel_with_error(anyhow::anyhow!("Oops"));
+        assert_eq!(child.generation(), 2);
+    }
+
+    #[derive(Copy, Clone)]
+    struct StopAfter(usize);
+
+    impl Task for StopAfter {
+        type Output = usize;
+
+        fn run(&self, cancel_tok: &CancellationToken) -> Result<usize, Error> {
+            let mut steps = 0;
+
+            while !cancel_tok.cancelled() {
+                steps += 1;
+                if steps == self.0 {
+                    cancel_tok.finish_early();
+                }
+            }
+
+            Ok(steps)
+        }
+    }
+
+    #[test]
+    fn tasks_can_stop_themselves() {
+        let handle = TaskHandle::spawn(StopAfter(5));
+
+        while !handle.is_finished() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(handle.status(), TaskStatus::Completed);
+        assert!(!handle.cancelled());
+        assert_eq!(handle.wait().unwrap(), 5);
+    }
+
+    #[test]
+    fn cancelled_tasks_report_their_status() {
+        let handle = TaskHandle::spawn(Spin);
+        assert_eq!(handle.status(), TaskStatus::Running);
+
+        handle.cancel();
+        while !handle.is_finished() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(handle.status(), TaskStatus::Cancelled);
+    }
+
+    #[test]
+    fn checkpoints_return_cancelled() {
+        let handle = TaskHandle::spawn(Checkpoints);
+        handle.cancel();
+
+        let err = handle.wait().unwrap_err();
+
+        assert!(err.downcast_ref::<Cancelled>().is_some());
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    struct Greet;
+
+    impl Task for Greet {
+        type Output = String;
+
+        fn run(&self, _: &CancellationToken) -> Result<String, Error> {
+            Ok(String::from("Hello, World!"))
+        }
+    }
+
+    #[test]
+    fn collect_type_erased_results() {
+        let spin = TaskHandle::spawn(Spin);
+        spin.cancel();
+        let greet = TaskHandle::spawn(Greet);
+
+        let results: Vec<Box<dyn Any + Send>> =
+            vec![spin.wait_boxed().unwrap(), greet.wait_boxed().unwrap()];
+
+        assert!(results[0].downcast_ref::<usize>().is_some());
+        assert_eq!(
+            results[1].downcast_ref::<String>().unwrap(),
+            "Hello, World!"
+        );
+    }
+
+    #[test]
+    fn spawn_type_erased_tasks() {
+        let tasks: Vec<Box<dyn ErasedTask>> =
+            vec![Box::new(Greet), Box::new(Map::new(Spin, |n| n * 2))];
+
+        let handles: Vec<_> =
+            tasks.into_iter().map(TaskHandle::spawn_erased).collect();
+        for handle in &handles {
+            handle.cancel();
+        }
+
+        let mut results = handles.into_iter().map(|h| h.wait().unwrap());
+        let greeting = results.next().unwrap();
+        assert_eq!(
+            greeting.downcast_ref::<String>().map(String::as_str),
+            Some("Hello, World!")
+        );
+        assert!(results.next().unwrap().is::<usize>());
+    }
+
+    #[test]
+    fn map_the_output_of_a_task() {
+        let task = Map::new(Spin, |spins: usize| format!("{} spins", spins));
+
+        let handle = TaskHandle::spawn(task);
+        handle.cancel();
+        let got = handle.wait().unwrap();
+
+        assert!(got.ends_with(" spins"));
+    }
+
+    #[cfg(not(feature = "no-catch-panic"))]
     #[derive(Copy, Clone)]
     struct PanicTask;
+    #[cfg(not(feature = "no-catch-panic"))]
     const PANIC_MESSAGE: &str = "Oops";
 
+    #[cfg(not(feature = "no-catch-panic"))]
     impl Task for PanicTask {
         type Output = ();
 
@@ -538,6 +2486,23 @@ mod tests {
         }
     }
 
+    #[cfg(not(feature = "no-catch-panic"))]
+    #[test]
+    fn handles_know_whether_the_task_panicked() {
+        let panicked = TaskHandle::spawn(PanicTask);
+        let errored: TaskHandle<()> =
+            spawn_fn(|_| Err(anyhow::anyhow!("Failed")));
+        let running = TaskHandle::spawn(Spin);
+
+        assert!(panicked.clone().wait().is_err());
+        assert!(errored.clone().wait().is_err());
+
+        assert_eq!(panicked.last_outcome_was_panic(), Some(true));
+        assert_eq!(errored.last_outcome_was_panic(), Some(false));
+        assert_eq!(running.last_outcome_was_panic(), None);
+    }
+
+    #[cfg(not(feature = "no-catch-panic"))]
     #[test]
     fn task_can_catch_panic_messages() {
         let task = PanicTask;
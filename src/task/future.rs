@@ -0,0 +1,107 @@
+use anyhow::Error;
+use std::{
+    future::Future,
+    pin::pin,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+    time::Duration,
+};
+
+use super::{CancellationToken, Cancelled, Task};
+
+/// How long to wait between checking the `CancellationToken` while the future
+/// is pending.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A [`Task`] which runs a [`Future`] to completion.
+///
+/// Each time the task is run, the provided closure is used to create a new
+/// future which is then driven by a minimal built-in executor on the task's
+/// thread. The [`CancellationToken`] is checked between polls, so a pending
+/// future will be abandoned with a [`Cancelled`] error shortly after the task
+/// is cancelled.
+///
+/// # Examples
+///
+/// ```rust
+/// use ffi_helpers::task::{FutureTask, TaskHandle};
+///
+/// let task = FutureTask::new(|| async { Ok(42) });
+///
+/// let got = TaskHandle::spawn(task).wait().unwrap();
+/// assert_eq!(got, 42);
+/// ```
+///
+/// [`Task`]: trait.Task.html
+/// [`Future`]: https://doc.rust-lang.org/std/future/trait.Future.html
+/// [`CancellationToken`]: struct.CancellationToken.html
+/// [`Cancelled`]: struct.Cancelled.html
+#[derive(Debug, Clone)]
+pub struct FutureTask<F> {
+    factory: F,
+}
+
+impl<F> FutureTask<F> {
+    /// Create a new `FutureTask` which uses `factory` to create the future.
+    pub fn new(factory: F) -> FutureTask<F> { FutureTask { factory } }
+}
+
+impl<F, Fut, T> Task for FutureTask<F>
+where
+    F: Fn() -> Fut + Send + Sync + Clone,
+    Fut: Future<Output = Result<T, Error>>,
+    T: Send + Sync,
+{
+    type Output = T;
+
+    fn run(&self, cancel_tok: &CancellationToken) -> Result<T, Error> {
+        let mut future = pin!((self.factory)());
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+
+        loop {
+            if cancel_tok.cancelled() {
+                return Err(Cancelled.into());
+            }
+
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(result) => return result,
+                Poll::Pending => thread::park_timeout(CANCEL_POLL_INTERVAL),
+            }
+        }
+    }
+}
+
+/// A waker which unparks the thread driving the future.
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) { self.0.unpark(); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::TaskHandle;
+
+    #[test]
+    fn run_a_future_in_the_background() {
+        let task = FutureTask::new(|| async { Ok(String::from("Done")) });
+
+        let got = TaskHandle::spawn(task).wait().unwrap();
+
+        assert_eq!(got, "Done");
+    }
+
+    #[test]
+    fn pending_futures_can_be_cancelled() {
+        let task = FutureTask::new(std::future::pending::<Result<(), Error>>);
+
+        let handle = TaskHandle::spawn(task);
+        handle.cancel();
+        let err = handle.wait().unwrap_err();
+
+        assert!(err.downcast_ref::<Cancelled>().is_some());
+    }
+}
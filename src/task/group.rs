@@ -0,0 +1,180 @@
+use anyhow::Error;
+use std::{
+    collections::VecDeque,
+    panic::UnwindSafe,
+    sync::{Arc, Condvar, Mutex},
+};
+
+use super::{lock, run_counted, Task, TaskHandle};
+
+/// A collection of tasks whose results can be retrieved in the order they
+/// finish, rather than the order they were spawned.
+///
+/// Every task in the group notifies a shared `Condvar` when it completes, so
+/// [`TaskGroup::wait_next()`] never gets stuck behind a slow task which was
+/// spawned early. This makes it easy to update a progress bar as each task
+/// finishes.
+///
+/// # Examples
+///
+/// ```rust
+/// use ffi_helpers::task::TaskGroup;
+/// # use ffi_helpers::{task::CancellationToken, Task};
+/// # use anyhow::Error;
+/// # #[derive(Clone)]
+/// # struct Download(u32);
+/// # impl Task for Download {
+/// #     type Output = u32;
+/// #     fn run(&self, _: &CancellationToken) -> Result<u32, Error> { Ok(self.0) }
+/// # }
+///
+/// let mut group = TaskGroup::new();
+///
+/// for i in 0..3 {
+///     group.spawn(Download(i));
+/// }
+///
+/// let mut finished = 0;
+/// while let Some((index, result)) = group.wait_next() {
+///     assert_eq!(result.unwrap(), index as u32);
+///     finished += 1;
+/// }
+///
+/// assert_eq!(finished, 3);
+/// ```
+///
+/// [`TaskGroup::wait_next()`]: #method.wait_next
+pub struct TaskGroup<T> {
+    handles: Vec<Option<TaskHandle<T>>>,
+    completions: Arc<Completions>,
+}
+
+impl<T: Send + 'static> TaskGroup<T> {
+    /// Create an empty `TaskGroup`.
+    pub fn new() -> TaskGroup<T> {
+        TaskGroup {
+            handles: Vec::new(),
+            completions: Arc::new(Completions::default()),
+        }
+    }
+
+    /// Spawn a `Task` on a background thread as part of this group, returning
+    /// its index.
+    pub fn spawn<K>(&mut self, task: K) -> usize
+    where
+        K: Task<Output = T> + UnwindSafe + 'static,
+    {
+        let index = self.handles.len();
+        let completions = Arc::clone(&self.completions);
+        let name = task.name().to_string();
+
+        let handle = TaskHandle::spawn_named(&name, move |cancel_tok| {
+            let _notify = Completed { completions, index };
+            run_counted(&task, cancel_tok)
+        });
+        self.handles.push(Some(handle));
+
+        index
+    }
+
+    /// The number of tasks whose results haven't been retrieved yet.
+    pub fn len(&self) -> usize { self.handles.iter().flatten().count() }
+
+    /// Have the results of all tasks been retrieved?
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    /// Block until the next task finishes, returning its index and result.
+    ///
+    /// Results are returned in completion order. Once every result has been
+    /// retrieved this returns `None`.
+    pub fn wait_next(&mut self) -> Option<(usize, Result<T, Error>)> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let index = self.completions.next();
+        let handle = self.handles[index]
+            .take()
+            .expect("Each task only completes once");
+
+        Some((index, handle.wait()))
+    }
+}
+
+impl<T: Send + 'static> Default for TaskGroup<T> {
+    fn default() -> TaskGroup<T> { TaskGroup::new() }
+}
+
+/// The indices of tasks which have finished, in the order they finished.
+#[derive(Debug, Default)]
+struct Completions {
+    finished: Mutex<VecDeque<usize>>,
+    ready: Condvar,
+}
+
+impl Completions {
+    fn next(&self) -> usize {
+        let mut finished = lock(&self.finished);
+
+        loop {
+            match finished.pop_front() {
+                Some(index) => return index,
+                None => {
+                    finished = self
+                        .ready
+                        .wait(finished)
+                        .unwrap_or_else(|e| e.into_inner());
+                },
+            }
+        }
+    }
+}
+
+/// Records that a task finished when dropped, so panicking tasks are
+/// reported too.
+struct Completed {
+    completions: Arc<Completions>,
+    index: usize,
+}
+
+impl Drop for Completed {
+    fn drop(&mut self) {
+        lock(&self.completions.finished).push_back(self.index);
+        self.completions.ready.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::CancellationToken;
+    use std::{thread, time::Duration};
+
+    #[derive(Debug, Clone)]
+    struct Sleep(u64);
+
+    impl Task for Sleep {
+        type Output = u64;
+
+        fn run(&self, _: &CancellationToken) -> Result<u64, Error> {
+            thread::sleep(Duration::from_millis(self.0));
+            Ok(self.0)
+        }
+    }
+
+    #[test]
+    fn results_come_back_in_completion_order() {
+        let mut group = TaskGroup::new();
+        group.spawn(Sleep(200));
+        group.spawn(Sleep(100));
+        group.spawn(Sleep(0));
+
+        let mut got = Vec::new();
+        while let Some((index, result)) = group.wait_next() {
+            got.push((index, result.unwrap()));
+        }
+
+        assert_eq!(got, vec![(2, 0), (1, 100), (0, 200)]);
+        assert!(group.is_empty());
+    }
+}
@@ -0,0 +1,73 @@
+use thiserror::Error;
+
+#[cfg(feature = "guard-handles")]
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+};
+
+/// The pointers handed out by functions generated with `export_task!()`, and
+/// how many times each was handed out (zero-sized results all share the same
+/// dangling pointer).
+#[cfg(feature = "guard-handles")]
+fn live_pointers() -> &'static Mutex<HashMap<usize, usize>> {
+    static LIVE: OnceLock<Mutex<HashMap<usize, usize>>> = OnceLock::new();
+    LIVE.get_or_init(Default::default)
+}
+
+/// Remember that `ptr` has been handed out to the caller.
+///
+/// This is a no-op unless the `guard-handles` feature is enabled.
+#[doc(hidden)]
+pub fn track_pointer<T>(ptr: *mut T) -> *mut T {
+    #[cfg(feature = "guard-handles")]
+    {
+        *super::lock(live_pointers())
+            .entry(ptr as usize)
+            .or_insert(0) += 1;
+    }
+
+    ptr
+}
+
+/// Check that `ptr` was handed out and hasn't already been destroyed,
+/// forgetting about it in the process.
+///
+/// If the pointer isn't known, `LAST_ERROR` is set to an [`UnknownPointer`]
+/// and you shouldn't free it. This always succeeds unless the `guard-handles`
+/// feature is enabled.
+///
+/// [`UnknownPointer`]: struct.UnknownPointer.html
+#[doc(hidden)]
+pub fn release_pointer<T>(ptr: *mut T) -> bool {
+    #[cfg(feature = "guard-handles")]
+    {
+        use std::collections::hash_map::Entry;
+
+        let mut live = super::lock(live_pointers());
+
+        match live.entry(ptr as usize) {
+            Entry::Occupied(mut entry) => {
+                *entry.get_mut() -= 1;
+                if *entry.get() == 0 {
+                    entry.remove();
+                }
+            },
+            Entry::Vacant(_) => {
+                crate::error_handling::update_last_error(UnknownPointer);
+                return false;
+            },
+        }
+    }
+
+    let _ = ptr;
+    true
+}
+
+/// A pointer passed to a destructor generated by `export_task!()` wasn't
+/// created by this library, or has already been destroyed.
+///
+/// This is only detected when the `guard-handles` feature is enabled.
+#[derive(Debug, Copy, Clone, PartialEq, Error)]
+#[error("The pointer is unknown or has already been destroyed")]
+pub struct UnknownPointer;
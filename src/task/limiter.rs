@@ -0,0 +1,322 @@
+use anyhow::Error;
+use std::{
+    collections::VecDeque,
+    panic::UnwindSafe,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+
+use super::{
+    default_stack_size, lock, run_counted, thread_builder, CancellationToken,
+    Cancelled, Deferred, Task, TaskHandle,
+};
+
+/// How often the dispatcher re-checks queued tasks for cancellation while it
+/// is waiting for a permit to be released.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A way to queue up any number of tasks while only letting a fixed number of
+/// them run at the same time.
+///
+/// Tasks spawned with [`ConcurrencyLimiter::spawn_limited()`] wait in a queue
+/// until a permit is available, and release it when they finish. A queued
+/// task doesn't get its own thread until it is given a permit. Instead, a
+/// single dispatcher thread per limiter hands out permits in the order tasks
+/// were queued, and only exists while there are tasks waiting.
+///
+/// Cancelling a task while it is still queued means it will never be run, and
+/// its result will be a [`Cancelled`] error.
+///
+/// Cloning a `ConcurrencyLimiter` gives you another handle to the same set of
+/// permits.
+///
+/// # Examples
+///
+/// ```rust
+/// use ffi_helpers::task::ConcurrencyLimiter;
+/// # use ffi_helpers::{task::CancellationToken, Task};
+/// # use anyhow::Error;
+/// # #[derive(Clone)]
+/// # struct Download(u32);
+/// # impl Task for Download {
+/// #     type Output = u32;
+/// #     fn run(&self, _: &CancellationToken) -> Result<u32, Error> { Ok(self.0) }
+/// # }
+///
+/// let limiter = ConcurrencyLimiter::new(2);
+///
+/// // only two downloads will ever be running at a time
+/// let handles: Vec<_> = (0..5)
+///     .map(|i| limiter.spawn_limited(Download(i)))
+///     .collect();
+///
+/// for (i, handle) in handles.into_iter().enumerate() {
+///     assert_eq!(handle.wait().unwrap(), i as u32);
+/// }
+/// ```
+///
+/// [`ConcurrencyLimiter::spawn_limited()`]: #method.spawn_limited
+/// [`Cancelled`]: struct.Cancelled.html
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimiter {
+    permits: Arc<Permits>,
+}
+
+impl ConcurrencyLimiter {
+    /// Create a `ConcurrencyLimiter` which lets at most `max_concurrent`
+    /// tasks run at once.
+    pub fn new(max_concurrent: usize) -> ConcurrencyLimiter {
+        ConcurrencyLimiter {
+            permits: Arc::new(Permits {
+                queue: Mutex::new(Queue {
+                    available: max_concurrent,
+                    pending: VecDeque::new(),
+                    dispatching: false,
+                }),
+                released: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Queue a `Task`, running it in the background once a permit becomes
+    /// available.
+    pub fn spawn_limited<K>(&self, task: K) -> TaskHandle<K::Output>
+    where
+        K: Task + UnwindSafe + 'static,
+        K::Output: 'static,
+    {
+        let permits = Arc::clone(&self.permits);
+        let name = task.name().to_string();
+
+        let (handle, deferred) = TaskHandle::deferred(move |cancel_tok| {
+            let _permit = Permit { permits };
+
+            if cancel_tok.cancelled() {
+                return Err(Error::from(Cancelled));
+            }
+
+            run_counted(&task, cancel_tok)
+        });
+        let queued = Queued {
+            name,
+            token: handle.inner.token.clone(),
+            task: deferred,
+        };
+
+        let mut queue = lock(&self.permits.queue);
+
+        if queue.available > 0 && queue.pending.is_empty() {
+            queue.available -= 1;
+            drop(queue);
+            queued.start();
+        } else {
+            queue.pending.push_back(queued);
+
+            if !queue.dispatching {
+                queue.dispatching = true;
+                let permits = Arc::clone(&self.permits);
+                thread::Builder::new()
+                    .name(String::from("concurrency-limiter"))
+                    .spawn(move || permits.dispatch())
+                    .expect("Unable to spawn the limiter's dispatcher thread");
+            }
+        }
+
+        handle
+    }
+}
+
+#[derive(Debug)]
+struct Permits {
+    queue: Mutex<Queue>,
+    released: Condvar,
+}
+
+impl Permits {
+    /// Start queued tasks as permits are released, and throw away any which
+    /// are cancelled while they wait, until the queue is empty.
+    fn dispatch(&self) {
+        loop {
+            let mut queue = lock(&self.queue);
+
+            let (cancelled, waiting): (VecDeque<_>, VecDeque<_>) = queue
+                .pending
+                .drain(..)
+                .partition(|queued| queued.token.cancelled());
+            queue.pending = waiting;
+
+            let ready = queue.available.min(queue.pending.len());
+            queue.available -= ready;
+            let ready: Vec<_> = queue.pending.drain(..ready).collect();
+
+            let finished = queue.pending.is_empty();
+            if finished {
+                queue.dispatching = false;
+            }
+            drop(queue);
+
+            for queued in cancelled {
+                queued.task.cancel();
+            }
+            for queued in ready {
+                queued.start();
+            }
+
+            if finished {
+                return;
+            }
+
+            let queue = lock(&self.queue);
+            if queue.available == 0 {
+                let _ = self.released.wait_timeout(queue, CANCEL_POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Queue {
+    available: usize,
+    pending: VecDeque<Queued>,
+    /// Is there a dispatcher thread looking after `pending`?
+    dispatching: bool,
+}
+
+/// A task which is waiting for a permit.
+#[derive(Debug)]
+struct Queued {
+    name: String,
+    token: CancellationToken,
+    task: Deferred,
+}
+
+impl Queued {
+    /// Run the task on its own thread, once it has been given a permit.
+    fn start(self) {
+        self.task
+            .spawn(thread_builder(Some(&self.name), default_stack_size()));
+    }
+}
+
+/// A permit to run, which is handed back when the task finishes (or panics).
+struct Permit {
+    permits: Arc<Permits>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        lock(&self.permits.queue).available += 1;
+        self.permits.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+        thread,
+    };
+
+    #[derive(Debug, Clone, Default)]
+    struct Tracked {
+        running: Arc<AtomicUsize>,
+        max_running: Arc<AtomicUsize>,
+    }
+
+    impl Task for Tracked {
+        type Output = ();
+
+        fn run(&self, _: &CancellationToken) -> Result<(), Error> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(20));
+            self.running.fetch_sub(1, Ordering::SeqCst);
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn no_more_than_the_limit_run_at_once() {
+        let limiter = ConcurrencyLimiter::new(2);
+        let task = Tracked::default();
+
+        let handles: Vec<_> = (0..5)
+            .map(|_| limiter.spawn_limited(task.clone()))
+            .collect();
+        for handle in handles {
+            handle.wait().unwrap();
+        }
+
+        assert!(task.max_running.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[derive(Debug, Clone)]
+    struct Blocker(Arc<AtomicBool>);
+
+    impl Task for Blocker {
+        type Output = ();
+
+        fn run(&self, cancel_tok: &CancellationToken) -> Result<(), Error> {
+            self.0.store(true, Ordering::SeqCst);
+
+            while !cancel_tok.cancelled() {
+                thread::sleep(Duration::from_millis(1));
+            }
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn cancelled_tasks_never_leave_the_queue() {
+        let limiter = ConcurrencyLimiter::new(1);
+        let first_started = Arc::new(AtomicBool::new(false));
+        let second_started = Arc::new(AtomicBool::new(false));
+
+        let first = limiter.spawn_limited(Blocker(Arc::clone(&first_started)));
+        while !first_started.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let second =
+            limiter.spawn_limited(Blocker(Arc::clone(&second_started)));
+
+        second.cancel();
+        let err = second.wait().unwrap_err();
+        assert!(err.is::<Cancelled>());
+
+        first.cancel();
+        first.wait().unwrap();
+        assert!(!second_started.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn queued_tasks_dont_get_a_thread_until_they_have_a_permit() {
+        let limiter = ConcurrencyLimiter::new(1);
+        let blocker_started = Arc::new(AtomicBool::new(false));
+        let task = Tracked::default();
+
+        let blocker =
+            limiter.spawn_limited(Blocker(Arc::clone(&blocker_started)));
+        while !blocker_started.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let queued: Vec<_> = (0..3)
+            .map(|_| limiter.spawn_limited(task.clone()))
+            .collect();
+
+        assert_eq!(lock(&limiter.permits.queue).pending.len(), 3);
+        assert_eq!(task.running.load(Ordering::SeqCst), 0);
+
+        blocker.cancel();
+        blocker.wait().unwrap();
+        for handle in queued {
+            handle.wait().unwrap();
+        }
+
+        assert!(lock(&limiter.permits.queue).pending.is_empty());
+        assert_eq!(task.max_running.load(Ordering::SeqCst), 1);
+    }
+}
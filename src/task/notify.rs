@@ -0,0 +1,102 @@
+use libc::c_int;
+use std::{os::unix::io::RawFd, sync::Mutex};
+
+use super::lock;
+
+/// A one-off notification which can be observed by waiting for a file
+/// descriptor to become readable (e.g. with `poll()` or `epoll`).
+///
+/// The underlying pipe is only created when someone asks for its file
+/// descriptor, so there is no cost if nobody is interested.
+#[derive(Debug, Default)]
+pub(crate) struct Notifier {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    notified: bool,
+    pipe: Option<Pipe>,
+}
+
+impl Notifier {
+    pub(crate) fn new() -> Notifier { Notifier::default() }
+
+    /// Get the read end of the pipe, creating it if necessary, or `-1` if the
+    /// pipe couldn't be created.
+    pub(crate) fn fd(&self) -> RawFd {
+        let mut state = lock(&self.state);
+
+        if state.pipe.is_none() {
+            let pipe = match Pipe::new() {
+                Some(pipe) => pipe,
+                None => return -1,
+            };
+
+            if state.notified {
+                pipe.signal();
+            }
+            state.pipe = Some(pipe);
+        }
+
+        state.pipe.as_ref().map_or(-1, |pipe| pipe.read)
+    }
+
+    /// Mark the notification as having happened, making the file descriptor
+    /// readable.
+    pub(crate) fn notify(&self) {
+        let mut state = lock(&self.state);
+
+        if !state.notified {
+            state.notified = true;
+
+            if let Some(ref pipe) = state.pipe {
+                pipe.signal();
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Pipe {
+    read: RawFd,
+    write: RawFd,
+}
+
+impl Pipe {
+    fn new() -> Option<Pipe> {
+        let mut fds: [c_int; 2] = [-1; 2];
+
+        unsafe {
+            if libc::pipe(fds.as_mut_ptr()) != 0 {
+                return None;
+            }
+            for &fd in &fds {
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            }
+        }
+
+        Some(Pipe {
+            read: fds[0],
+            write: fds[1],
+        })
+    }
+
+    /// Write a single byte to the pipe. Nobody ever reads it, so the read end
+    /// will stay readable from now on.
+    fn signal(&self) {
+        let byte = 1_u8;
+        unsafe {
+            libc::write(self.write, &byte as *const u8 as *const _, 1);
+        }
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use anyhow::Error;
+use libc::c_int;
+use serde::Serialize;
+use std::{panic::UnwindSafe, sync::Mutex};
+
+use super::{lock, run_counted, Task, TaskHandle};
+use crate::{error_handling, strings::copy_into_buffer};
+
+/// A handle to a background task whose output is serialized as JSON, letting
+/// C callers retrieve it without needing a `#[repr(C)]` type.
+///
+/// The result is retrieved by copying it into a caller-provided buffer using
+/// the same "size then fill" convention as the error handling functions.
+/// Because the serialized result is cached by the handle, you can keep
+/// retrying with a bigger buffer.
+///
+/// This is normally used via the [`export_serializable_task!()`] macro.
+///
+/// [`export_serializable_task!()`]: ../macro.export_serializable_task.html
+pub struct SerializedTaskHandle {
+    handle: TaskHandle<Vec<u8>>,
+    result: Mutex<Option<Vec<u8>>>,
+}
+
+impl SerializedTaskHandle {
+    /// Spawn a `Task` in the background, serializing its output when it
+    /// completes.
+    pub fn spawn<K>(task: K) -> SerializedTaskHandle
+    where
+        K: Task + UnwindSafe + 'static,
+        K::Output: Serialize + 'static,
+    {
+        let name = task.name().to_string();
+
+        let handle = TaskHandle::spawn_named(&name, move |cancel_tok| {
+            let output = run_counted(&task, cancel_tok)?;
+            serde_json::to_vec(&output).map_err(Error::from)
+        });
+
+        SerializedTaskHandle {
+            handle,
+            result: Mutex::new(None),
+        }
+    }
+
+    /// If the task has finished, copy its serialized output into `buffer`.
+    ///
+    /// This returns the number of bytes written, or the negative of the
+    /// required length if the buffer is too small. You'll get `0` if the task
+    /// hasn't finished yet or it failed, in which case `LAST_ERROR` is
+    /// updated.
+    pub fn poll_into(&self, buffer: &mut [u8]) -> c_int {
+        let mut result = lock(&self.result);
+
+        if result.is_none() {
+            match self.handle.poll() {
+                Some(Ok(bytes)) => *result = Some(bytes),
+                Some(Err(e)) => {
+                    error_handling::update_last_error(e);
+                    return 0;
+                },
+                None => return 0,
+            }
+        }
+
+        copy_into_buffer(result.as_deref().unwrap_or_default(), buffer, false)
+    }
+
+    /// Block until the task has finished, then copy its serialized output
+    /// into `buffer`.
+    ///
+    /// The return value is the same as [`poll_into()`].
+    ///
+    /// [`poll_into()`]: #method.poll_into
+    pub fn wait_into(&self, buffer: &mut [u8]) -> c_int {
+        let mut result = lock(&self.result);
+
+        if result.is_none() {
+            match self.handle.clone().wait() {
+                Ok(bytes) => *result = Some(bytes),
+                Err(e) => {
+                    error_handling::update_last_error(e);
+                    return 0;
+                },
+            }
+        }
+
+        copy_into_buffer(result.as_deref().unwrap_or_default(), buffer, false)
+    }
+
+    /// Cancel the background task.
+    pub fn cancel(&self) { self.handle.cancel(); }
+
+    /// Has this task been cancelled?
+    pub fn cancelled(&self) -> bool { self.handle.cancelled() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::CancellationToken;
+    use serde::Serialize;
+
+    #[derive(Debug, Clone, Serialize)]
+    pub struct Stats {
+        name: String,
+        count: u32,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct GetStats;
+
+    impl Task for GetStats {
+        type Output = Stats;
+
+        fn run(&self, _: &CancellationToken) -> Result<Stats, Error> {
+            Ok(Stats {
+                name: String::from("widgets"),
+                count: 3,
+            })
+        }
+    }
+
+    crate::export_serializable_task! {
+        Task: GetStats;
+        spawn: stats_spawn;
+        wait: stats_wait;
+        poll: stats_poll;
+        cancel: stats_cancel;
+        handle_destroy: stats_handle_destroy;
+    }
+
+    #[test]
+    fn serialize_the_output_into_a_buffer() {
+        let expected = br#"{"name":"widgets","count":3}"#;
+
+        unsafe {
+            let handle = stats_spawn(&GetStats);
+
+            // the buffer is too small, so we're told how much space we need
+            let mut buffer = vec![0_u8; 4];
+            let ret = stats_wait(handle, buffer.as_mut_ptr(), 4);
+            assert_eq!(ret, -(expected.len() as c_int));
+
+            // then try again with a big enough buffer
+            buffer.resize(-ret as usize, 0);
+            let ret = stats_poll(handle, buffer.as_mut_ptr(), -ret);
+            assert_eq!(ret, expected.len() as c_int);
+            assert_eq!(buffer, expected);
+
+            stats_handle_destroy(handle);
+        }
+    }
+
+    #[test]
+    fn negative_buffer_lengths_are_rejected() {
+        use crate::error_handling::COPY_INVALID_LENGTH;
+
+        unsafe {
+            let handle = stats_spawn(&GetStats);
+            let mut buffer = [0_u8; 4];
+
+            let ret = stats_wait(handle, buffer.as_mut_ptr(), -4);
+
+            assert_eq!(ret, COPY_INVALID_LENGTH);
+            assert_eq!(buffer, [0; 4]);
+            stats_handle_destroy(handle);
+        }
+    }
+
+    mod restricted {
+        use super::GetStats;
+
+        crate::export_serializable_task! {
+            vis: pub(crate);
+            Task: GetStats;
+            spawn: restricted_stats_spawn;
+            cancelled: restricted_stats_cancelled;
+            handle_destroy: restricted_stats_handle_destroy;
+        }
+    }
+
+    #[test]
+    fn generate_functions_with_a_custom_visibility() {
+        use self::restricted::*;
+
+        unsafe {
+            let handle = restricted_stats_spawn(&GetStats);
+            assert_eq!(restricted_stats_cancelled(handle), 0);
+            restricted_stats_handle_destroy(handle);
+        }
+    }
+
+    #[cfg(feature = "guard-handles")]
+    #[test]
+    fn destroying_a_handle_twice_is_detected() {
+        use crate::{error_handling::*, task::UnknownPointer};
+
+        unsafe {
+            let handle = stats_spawn(&GetStats);
+
+            clear_last_error();
+            stats_handle_destroy(handle);
+            assert!(!last_error_is::<UnknownPointer>());
+
+            stats_handle_destroy(handle);
+            assert!(last_error_is::<UnknownPointer>());
+            clear_last_error();
+        }
+    }
+}
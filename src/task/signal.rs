@@ -0,0 +1,171 @@
+use libc::{c_int, c_void, siginfo_t};
+use std::{
+    io, mem, ptr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+/// One more than the largest signal number we are willing to handle.
+const MAX_SIGNALS: usize = 65;
+
+/// How many times each signal has been received since its handler was
+/// installed.
+static RECEIVED: [AtomicUsize; MAX_SIGNALS] =
+    [const { AtomicUsize::new(0) }; MAX_SIGNALS];
+static INSTALLED: [AtomicBool; MAX_SIGNALS] =
+    [const { AtomicBool::new(false) }; MAX_SIGNALS];
+/// The handler which was installed before ours, so we can pass the signal
+/// along to it.
+static PREVIOUS: [AtomicUsize; MAX_SIGNALS] =
+    [const { AtomicUsize::new(libc::SIG_DFL) }; MAX_SIGNALS];
+/// Does the previous handler take a `siginfo_t` (i.e. was it installed with
+/// `SA_SIGINFO`)?
+static PREVIOUS_SIGINFO: [AtomicBool; MAX_SIGNALS] =
+    [const { AtomicBool::new(false) }; MAX_SIGNALS];
+
+/// Keeps track of whether a particular signal has arrived since the watch
+/// was created.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct SignalWatch {
+    index: usize,
+    seen: usize,
+}
+
+impl SignalWatch {
+    /// Start watching for `signum`, installing our handler if necessary.
+    pub(crate) fn new(signum: c_int) -> Result<SignalWatch, io::Error> {
+        let index = usize::try_from(signum)
+            .ok()
+            .filter(|&index| index > 0 && index < MAX_SIGNALS)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+
+        if !INSTALLED[index].swap(true, Ordering::SeqCst) {
+            if let Err(e) = unsafe { install_handler(signum, index) } {
+                INSTALLED[index].store(false, Ordering::SeqCst);
+                return Err(e);
+            }
+        }
+
+        Ok(SignalWatch {
+            index,
+            seen: RECEIVED[index].load(Ordering::SeqCst),
+        })
+    }
+
+    /// Has the signal been received since this watch was created?
+    pub(crate) fn fired(&self) -> bool {
+        RECEIVED[self.index].load(Ordering::SeqCst) != self.seen
+    }
+}
+
+unsafe fn install_handler(
+    signum: c_int,
+    index: usize,
+) -> Result<(), io::Error> {
+    // remember the existing handler before ours can run
+    let mut previous: libc::sigaction = mem::zeroed();
+    if libc::sigaction(signum, ptr::null(), &mut previous) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    PREVIOUS[index].store(previous.sa_sigaction, Ordering::SeqCst);
+    PREVIOUS_SIGINFO[index]
+        .store(previous.sa_flags & libc::SA_SIGINFO != 0, Ordering::SeqCst);
+
+    let mut action: libc::sigaction = mem::zeroed();
+    action.sa_sigaction = record_signal
+        as extern "C" fn(c_int, *mut siginfo_t, *mut c_void)
+        as usize;
+    action.sa_flags = libc::SA_RESTART | libc::SA_SIGINFO;
+    libc::sigemptyset(&mut action.sa_mask);
+
+    if libc::sigaction(signum, &action, ptr::null_mut()) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// The signal handler. Incrementing an atomic is async-signal-safe, anything
+/// more complicated (locking, allocating, notifying) is not.
+///
+/// Afterwards the signal is passed along to the previous handler, unless that
+/// was the default action or the signal was being ignored.
+extern "C" fn record_signal(
+    signum: c_int,
+    info: *mut siginfo_t,
+    context: *mut c_void,
+) {
+    let index = match usize::try_from(signum) {
+        Ok(index) if index < MAX_SIGNALS => index,
+        _ => return,
+    };
+
+    RECEIVED[index].fetch_add(1, Ordering::SeqCst);
+
+    let previous = PREVIOUS[index].load(Ordering::SeqCst);
+    if previous == libc::SIG_DFL || previous == libc::SIG_IGN {
+        return;
+    }
+
+    unsafe {
+        if PREVIOUS_SIGINFO[index].load(Ordering::SeqCst) {
+            let handler: extern "C" fn(c_int, *mut siginfo_t, *mut c_void) =
+                mem::transmute(previous);
+            handler(signum, info, context);
+        } else {
+            let handler: extern "C" fn(c_int) = mem::transmute(previous);
+            handler(signum);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::CancellationToken;
+
+    #[test]
+    fn the_token_is_cancelled_when_the_signal_arrives() {
+        let cancel_tok = CancellationToken::on_signal(libc::SIGUSR1);
+        assert!(!cancel_tok.cancelled());
+
+        unsafe {
+            libc::raise(libc::SIGUSR1);
+        }
+
+        assert!(cancel_tok.cancelled());
+        let later = CancellationToken::on_signal(libc::SIGUSR1);
+        assert!(!later.cancelled(), "Old signals are ignored");
+    }
+
+    #[test]
+    fn existing_handlers_are_still_called() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        extern "C" fn existing_handler(_: c_int) {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+        }
+
+        unsafe {
+            libc::signal(
+                libc::SIGUSR2,
+                existing_handler as extern "C" fn(c_int) as usize,
+            );
+        }
+        let cancel_tok = CancellationToken::on_signal(libc::SIGUSR2);
+
+        unsafe {
+            libc::raise(libc::SIGUSR2);
+        }
+
+        assert!(cancel_tok.cancelled());
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn invalid_signals_are_rejected() {
+        assert!(SignalWatch::new(0).is_err());
+        assert!(SignalWatch::new(-1).is_err());
+        assert!(SignalWatch::new(libc::SIGKILL).is_err());
+        assert!(!INSTALLED[libc::SIGKILL as usize].load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,68 @@
+//! Helpers for testing FFI bindings built on top of this crate.
+//!
+//! These are only available when the `test-util` feature is enabled.
+
+use std::fmt::{Debug, Display};
+
+use crate::{error_handling, Nullable};
+
+/// Assert that a function returned its `Nullable::NULL` sentinel and set
+/// `LAST_ERROR` to an error of type `E`.
+///
+/// The `LAST_ERROR` is cleared in the process.
+///
+/// # Examples
+///
+/// ```rust
+/// use ffi_helpers::{test_util, NullPointer};
+///
+/// unsafe extern "C" fn first_byte(data: *const u8) -> u8 {
+///     ffi_helpers::null_pointer_check!(data);
+///     *data
+/// }
+///
+/// let ret = unsafe { first_byte(std::ptr::null()) };
+/// test_util::assert_null_with_error::<_, NullPointer>(ret);
+/// ```
+#[track_caller]
+pub fn assert_null_with_error<T, E>(value: T)
+where
+    T: Nullable,
+    E: Display + Debug + Send + Sync + 'static,
+{
+    assert!(value.is_null(), "Expected the NULL sentinel");
+
+    match error_handling::take_last_error() {
+        Some(e) => assert!(
+            e.is::<E>(),
+            "Expected a {} but got \"{:#}\"",
+            std::any::type_name::<E>(),
+            e
+        ),
+        None => panic!(
+            "Expected a {} but there was no error",
+            std::any::type_name::<E>()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{update_last_error, Misaligned, NullPointer};
+
+    #[test]
+    fn null_with_the_expected_error() {
+        update_last_error(NullPointer);
+
+        assert_null_with_error::<_, NullPointer>(0_i32);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected a ffi_helpers::nullable::Misaligned")]
+    fn null_with_the_wrong_error() {
+        update_last_error(NullPointer);
+
+        assert_null_with_error::<_, Misaligned>(0_i32);
+    }
+}
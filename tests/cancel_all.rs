@@ -0,0 +1,22 @@
+//! Checks for `cancel_all_tasks()`, see the `common` module for why this is
+//! its own test binary.
+
+mod common;
+
+use common::Spin;
+use ffi_helpers::task::{self, TaskHandle};
+
+#[test]
+fn cancel_every_task_at_once() {
+    let handles: Vec<_> = (0..3).map(|_| TaskHandle::spawn(Spin)).collect();
+    // a task whose handles have all been dropped is already cancelled
+    drop(TaskHandle::spawn(Spin));
+    assert!(handles.iter().all(|handle| !handle.cancelled()));
+
+    task::cancel_all_tasks();
+
+    for handle in handles {
+        assert!(handle.cancelled());
+        handle.wait().unwrap();
+    }
+}
@@ -0,0 +1,50 @@
+//! Tests for the `#[catch_panics]` attribute.
+
+#![cfg(all(feature = "macros", not(feature = "no-catch-panic")))]
+
+use ffi_helpers::{catch_panics, error_handling, panic::Panic};
+use libc::c_int;
+
+#[catch_panics]
+#[no_mangle]
+unsafe extern "C" fn checked_divide(
+    numerator: c_int,
+    denominator: c_int,
+) -> c_int {
+    if denominator == 0 {
+        panic!("Attempted to divide by zero");
+    }
+
+    numerator / denominator
+}
+
+#[catch_panics]
+extern "C" fn early_return(value: *const u8) -> *const u8 {
+    if value.is_null() {
+        return std::ptr::null();
+    }
+
+    value
+}
+
+#[test]
+fn panics_are_caught_and_recorded() {
+    error_handling::clear_last_error();
+
+    let got = unsafe { checked_divide(1, 0) };
+
+    assert_eq!(got, 0);
+    let err = error_handling::take_last_error().unwrap();
+    let panic = err.downcast_ref::<Panic>().unwrap();
+    assert_eq!(panic.message, "Attempted to divide by zero");
+}
+
+#[test]
+fn functions_which_dont_panic_are_unaffected() {
+    error_handling::clear_last_error();
+
+    assert_eq!(unsafe { checked_divide(42, 2) }, 21);
+    let value = 5_u8;
+    assert_eq!(early_return(&value), &value as *const u8);
+    assert!(error_handling::take_last_error().is_none());
+}
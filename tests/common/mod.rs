@@ -0,0 +1,24 @@
+//! Fixtures shared by the integration tests.
+//!
+//! Task counts and cancellation are process-wide, so each test which checks
+//! them gets its own test binary where no other tasks are running.
+
+use anyhow::Error;
+use ffi_helpers::{task::CancellationToken, Task};
+use std::{thread, time::Duration};
+
+/// A task which runs until it is cancelled.
+#[derive(Debug, Clone, Copy)]
+pub struct Spin;
+
+impl Task for Spin {
+    type Output = ();
+
+    fn run(&self, cancel_tok: &CancellationToken) -> Result<(), Error> {
+        while !cancel_tok.cancelled() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        Ok(())
+    }
+}
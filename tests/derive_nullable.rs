@@ -0,0 +1,61 @@
+//! Tests for `#[derive(Nullable)]`.
+
+#![cfg(all(feature = "macros", not(feature = "no-catch-panic")))]
+
+use ffi_helpers::{error_handling, panic::Panic, Nullable};
+use libc::c_int;
+
+#[derive(Debug, Copy, Clone, PartialEq, Nullable)]
+#[repr(C)]
+pub struct Point {
+    x: c_int,
+    y: c_int,
+    label: *const u8,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Nullable)]
+#[repr(C)]
+pub struct Wrapper<T>(T, u8);
+
+extern "C" fn midpoint(a: Point, b: Point) -> Point {
+    ffi_helpers::catch_panic!({
+        if a.label.is_null() || b.label.is_null() {
+            panic!("Points must have a label");
+        }
+
+        Ok(Point {
+            x: (a.x + b.x) / 2,
+            y: (a.y + b.y) / 2,
+            label: a.label,
+        })
+    })
+}
+
+#[test]
+fn the_null_struct_is_all_zeroes() {
+    let null = Point::NULL;
+
+    assert_eq!((null.x, null.y), (0, 0));
+    assert!(null.label.is_null());
+    assert!(null.is_null());
+    assert!(!Point { x: 1, ..null }.is_null());
+    assert!(Wrapper::<Point>::NULL.is_null());
+    assert!(!Wrapper(Point::NULL, 1).is_null());
+}
+
+#[test]
+fn catch_panic_returns_the_null_struct() {
+    error_handling::clear_last_error();
+    let label = &42_u8 as *const u8;
+    let a = Point { x: 0, y: 0, label };
+    let b = Point { x: 4, y: 2, label };
+
+    assert_eq!(midpoint(a, b), Point { x: 2, y: 1, label });
+
+    let got = midpoint(a, Point::NULL);
+
+    assert!(got.is_null());
+    let err = error_handling::take_last_error().unwrap();
+    let panic = err.downcast_ref::<Panic>().unwrap();
+    assert_eq!(panic.message, "Points must have a label");
+}
@@ -0,0 +1,347 @@
+//! Tests for settings which change the behaviour of the whole process.
+//!
+//! These live in their own test binary so they can't interfere with the unit
+//! tests, which run concurrently and expect the default configuration.
+
+use ffi_helpers::{
+    error_handling::{self, *},
+    task::{spawn_fn, CancellationToken, TaskHandle},
+    Task,
+};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+fn lock_global_config() -> MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[test]
+fn error_messages_include_the_prefix() {
+    let _guard = lock_global_config();
+    clear_last_error();
+    set_error_prefix("[mylib] ");
+
+    update_last_error(anyhow::anyhow!("Something went wrong"));
+    let msg = error_message().unwrap();
+    let length = last_error_length();
+    set_error_prefix("");
+
+    assert_eq!(msg, "[mylib] Something went wrong");
+    assert_eq!(length as usize, msg.len() + 1);
+}
+
+#[test]
+fn reset_config_restores_the_defaults() {
+    let _guard = lock_global_config();
+    clear_last_error();
+    set_error_prefix("[mylib] ");
+    set_error_transform(Box::new(|e| Some(e.context("Transformed"))));
+    set_store_rendered(true);
+    register_error_code::<StringError>(42);
+    ffi_helpers::panic::set_rethrow_after_catch(true);
+
+    error_handling::reset_config();
+    ffi_helpers::panic::reset_config();
+
+    update_last_error(anyhow::anyhow!("Something went wrong"));
+    assert_eq!(error_message().unwrap(), "Something went wrong");
+    assert!(!last_error_is::<StringError>());
+    set_last_error_str("Not registered");
+    assert_eq!(last_error_code(), DEFAULT_ERROR_CODE);
+    #[cfg(not(feature = "no-catch-panic"))]
+    {
+        let got: Result<(), ()> = ffi_helpers::catch_panic(|| panic!("Oops"));
+        assert!(got.is_err());
+    }
+    clear_last_error();
+}
+
+#[test]
+fn errors_can_be_rendered_when_they_are_set() {
+    let _guard = lock_global_config();
+    clear_last_error();
+    set_store_rendered(true);
+
+    let err = anyhow::anyhow!("Root cause").context("Outer");
+    update_last_error_with_code(err, 42);
+    let is_string_error = last_error_is::<StringError>();
+    let code = last_error_code();
+    let msg = error_message().unwrap();
+    set_store_rendered(false);
+    clear_last_error();
+
+    assert!(is_string_error);
+    assert_eq!(code, 42);
+    assert_eq!(msg, "Outer: Root cause");
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("The config is invalid")]
+struct InvalidConfig;
+
+#[test]
+fn error_codes_can_be_registered_at_runtime() {
+    let _guard = lock_global_config();
+    clear_last_error();
+    register_error_code::<InvalidConfig>(7);
+
+    update_last_error(InvalidConfig);
+    let registered = last_error_code();
+    update_last_error(anyhow::Error::new(InvalidConfig).context("Loading"));
+    let with_context = last_error_code();
+    update_last_error_with_code(InvalidConfig, 3);
+    let explicit = last_error_code();
+    update_last_error(anyhow::anyhow!("Something else"));
+    let unregistered = last_error_code();
+    clear_error_codes();
+    clear_last_error();
+
+    assert_eq!(registered, 7);
+    assert_eq!(with_context, 7);
+    assert_eq!(explicit, 3);
+    assert_eq!(unregistered, DEFAULT_ERROR_CODE);
+}
+
+#[test]
+fn several_error_hooks_can_observe_errors() {
+    let _guard = lock_global_config();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+
+    let first = {
+        let seen = Arc::clone(&seen);
+        add_error_hook(Arc::new(move |e| {
+            seen.lock().unwrap().push(format!("first: {}", e));
+        }))
+    };
+    let second = {
+        let seen = Arc::clone(&seen);
+        add_error_hook(Arc::new(move |e| {
+            seen.lock().unwrap().push(format!("second: {}", e));
+        }))
+    };
+
+    update_last_error(anyhow::anyhow!("One"));
+    assert!(remove_error_hook(first));
+    assert!(!remove_error_hook(first));
+    update_last_error(anyhow::anyhow!("Two"));
+    remove_error_hook(second);
+    update_last_error(anyhow::anyhow!("Three"));
+    clear_last_error();
+
+    assert_eq!(
+        *seen.lock().unwrap(),
+        ["first: One", "second: One", "second: Two"]
+    );
+}
+
+#[test]
+fn the_code_message_format_is_configurable() {
+    let _guard = lock_global_config();
+    clear_last_error();
+    set_code_message_format("{message} (code {code}, {unknown})");
+
+    update_last_error_with_code(anyhow::anyhow!("Oops"), 7);
+    let mut buffer = [0_u8; 64];
+    let ret =
+        unsafe { error_message_with_code_utf8(buffer.as_mut_ptr().cast(), 64) };
+    error_handling::reset_config();
+    clear_last_error();
+
+    let got = std::str::from_utf8(&buffer[..ret as usize - 1]).unwrap();
+    assert_eq!(got, "Oops (code 7, {unknown})");
+}
+
+#[test]
+fn error_hooks_run_before_the_error_is_stored() {
+    let _guard = lock_global_config();
+    clear_last_error();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+
+    let hook = {
+        let seen = Arc::clone(&seen);
+        add_error_hook(Arc::new(move |_| {
+            let mut buffer = [0_u8; 32];
+            let copied =
+                unsafe { error_message_utf8(buffer.as_mut_ptr().cast(), 32) };
+            seen.lock().unwrap().push((
+                error_message(),
+                last_error_length(),
+                copied,
+            ));
+        }))
+    };
+
+    update_last_error(anyhow::anyhow!("First"));
+    update_last_error(anyhow::anyhow!("Second"));
+    remove_error_hook(hook);
+    clear_last_error();
+
+    // hooks are called before the new error is stored, so they see the
+    // previous one
+    assert_eq!(
+        *seen.lock().unwrap(),
+        [(None, 0, 0), (Some(String::from("First")), 6, 6)]
+    );
+}
+
+#[test]
+fn error_hooks_are_rate_limited() {
+    let _guard = lock_global_config();
+    clear_last_error();
+    let calls = Arc::new(Mutex::new(0));
+    let hook = {
+        let calls = Arc::clone(&calls);
+        add_error_hook(Arc::new(move |_| *calls.lock().unwrap() += 1))
+    };
+    let coalesced_before = coalesced_error_count();
+    set_error_rate_limit(5);
+
+    for i in 0..100 {
+        update_last_error(anyhow::anyhow!("Error #{}", i));
+    }
+    let msg = error_message();
+    remove_error_hook(hook);
+    set_error_rate_limit(0);
+    clear_last_error();
+
+    // the errors are all stored, but only the first few reach the hook
+    assert_eq!(msg.unwrap(), "Error #99");
+    assert_eq!(*calls.lock().unwrap(), 5);
+    assert_eq!(coalesced_error_count() - coalesced_before, 95);
+}
+
+#[test]
+fn task_errors_reach_the_handle_untouched() {
+    let _guard = lock_global_config();
+    clear_last_error();
+    set_store_rendered(true);
+    register_error_code::<InvalidConfig>(7);
+
+    let failed: TaskHandle<()> = spawn_fn(|_| Err(InvalidConfig.into()));
+    let err = failed.wait().unwrap_err();
+    let is_invalid_config = err.is::<InvalidConfig>();
+    update_last_error(err);
+    let code = last_error_code();
+
+    set_store_rendered(false);
+    clear_error_codes();
+    clear_last_error();
+
+    assert!(is_invalid_config);
+    assert_eq!(code, 7);
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Fail;
+
+impl Task for Fail {
+    type Output = ();
+
+    fn run(&self, _: &CancellationToken) -> Result<(), anyhow::Error> {
+        Err(anyhow::anyhow!("Task failed"))
+    }
+}
+
+ffi_helpers::export_task! {
+    Task: Fail;
+    spawn: fail_spawn;
+    wait: fail_wait;
+}
+
+#[test]
+fn hooks_fire_once_for_a_failed_task() {
+    let _guard = lock_global_config();
+    clear_last_error();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let hook = {
+        let seen = Arc::clone(&seen);
+        add_error_hook(Arc::new(move |e| {
+            seen.lock().unwrap().push(e.to_string());
+        }))
+    };
+
+    let got = unsafe { fail_wait(fail_spawn(&Fail)) };
+    remove_error_hook(hook);
+    clear_last_error();
+
+    assert!(got.is_null());
+    assert_eq!(*seen.lock().unwrap(), ["Task failed"]);
+}
+
+#[test]
+fn the_transform_runs_once_for_a_failed_task() {
+    let _guard = lock_global_config();
+    clear_last_error();
+    set_error_transform(Box::new(|e| Some(e.context("redacted"))));
+
+    let failed: TaskHandle<()> = spawn_fn(|_| Err(anyhow::anyhow!("fail")));
+    update_last_error(failed.wait().unwrap_err());
+    let transformed = error_message();
+
+    set_error_transform(Box::new(|_| None));
+    let suppressed: TaskHandle<()> = spawn_fn(|_| Err(anyhow::anyhow!("fail")));
+    update_last_error(suppressed.wait().unwrap_err());
+    let after_suppressing = error_message();
+
+    clear_error_transform();
+    clear_last_error();
+
+    assert_eq!(transformed.unwrap(), "redacted: fail");
+    assert_eq!(after_suppressing, None);
+}
+
+#[cfg(not(feature = "no-catch-panic"))]
+#[test]
+fn panicking_tasks_are_reported_with_rendered_errors() {
+    let _guard = lock_global_config();
+    set_store_rendered(true);
+
+    let handle: TaskHandle<()> = spawn_fn(|_| panic!("Oops"));
+    let err = handle.clone().wait().unwrap_err();
+
+    set_store_rendered(false);
+    clear_last_error();
+
+    assert_eq!(handle.last_outcome_was_panic(), Some(true));
+    assert!(err.is::<ffi_helpers::panic::Panic>());
+}
+
+#[test]
+fn dump_the_configuration() {
+    let _guard = lock_global_config();
+    set_error_prefix("[mylib] ");
+    set_error_transform(Box::new(Some));
+    ffi_helpers::panic::set_rethrow_after_catch(true);
+
+    let dump = ffi_helpers::debug_dump_config();
+    error_handling::reset_config();
+    ffi_helpers::panic::reset_config();
+
+    assert!(dump.contains("error_handling.prefix: \"[mylib] \"\n"));
+    assert!(dump.contains("error_handling.transform_installed: true\n"));
+    assert!(dump.contains("error_handling.error_rate_limit: 0\n"));
+    assert!(dump.contains("panic.rethrow_after_catch: true\n"));
+    assert!(dump.contains("task.default_stack_size: OS default\n"));
+}
+
+#[cfg(not(feature = "no-catch-panic"))]
+#[test]
+fn rethrow_panics_after_recording_them() {
+    let _guard = lock_global_config();
+    clear_last_error();
+    ffi_helpers::panic::set_rethrow_after_catch(true);
+
+    let outer = std::panic::catch_unwind(|| {
+        let _: Result<(), ()> = ffi_helpers::catch_panic(|| panic!("Oops"));
+    });
+    ffi_helpers::panic::set_rethrow_after_catch(false);
+
+    // the original payload is preserved
+    let payload = outer.unwrap_err();
+    assert_eq!(payload.downcast_ref::<&str>(), Some(&"Oops"));
+
+    // and the panic was still recorded
+    let err = take_last_error().unwrap();
+    let panic = err.downcast_ref::<ffi_helpers::panic::Panic>().unwrap();
+    assert_eq!(panic.message, "Oops");
+}
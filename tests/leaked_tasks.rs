@@ -0,0 +1,71 @@
+//! Checks for `leaked_task_count()`, see the `common` module for why this is
+//! its own test binary.
+
+mod common;
+
+use anyhow::Error;
+use common::Spin;
+use ffi_helpers::{
+    error_handling::{add_error_hook, remove_error_hook},
+    task::{leaked_task_count, CancellationToken, LeakedTask, TaskHandle},
+    Task,
+};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// A task which ignores its `CancellationToken` and only stops when told to.
+#[derive(Debug, Clone)]
+struct Stubborn(Arc<AtomicBool>);
+
+impl Task for Stubborn {
+    type Output = ();
+
+    fn run(&self, _: &CancellationToken) -> Result<(), Error> {
+        while !self.0.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn dropping_a_stubborn_task_leaks_it() {
+    let warnings = Arc::new(AtomicUsize::new(0));
+    let warnings_2 = Arc::clone(&warnings);
+    let hook = add_error_hook(Arc::new(move |e: &Error| {
+        if e.is::<LeakedTask>() {
+            warnings_2.fetch_add(1, Ordering::SeqCst);
+        }
+    }));
+    let stop = Arc::new(AtomicBool::new(false));
+    assert_eq!(leaked_task_count(), 0);
+
+    let cancelled = TaskHandle::spawn(Stubborn(Arc::clone(&stop)));
+    cancelled.cancel();
+    thread::sleep(Duration::from_millis(20));
+    drop(cancelled);
+
+    assert_eq!(leaked_task_count(), 1);
+    assert_eq!(warnings.load(Ordering::SeqCst), 1);
+
+    // dropping the handle cancels a running task, which is fine as long as
+    // it stops by itself
+    drop(TaskHandle::spawn(Spin));
+
+    assert_eq!(leaked_task_count(), 1);
+    assert_eq!(warnings.load(Ordering::SeqCst), 1);
+
+    // once the task finally stops, it is no longer leaked
+    stop.store(true, Ordering::SeqCst);
+    while leaked_task_count() > 0 {
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert!(remove_error_hook(hook));
+}
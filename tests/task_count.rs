@@ -0,0 +1,29 @@
+//! Checks for `active_task_count()`, see the `common` module for why this is
+//! its own test binary.
+
+mod common;
+
+use common::Spin;
+use ffi_helpers::task::{active_task_count, TaskHandle};
+use std::{thread, time::Duration};
+
+#[test]
+fn count_running_tasks() {
+    assert_eq!(active_task_count(), 0);
+
+    let first = TaskHandle::spawn(Spin);
+    let second = TaskHandle::spawn(Spin);
+
+    // the background threads may take a moment to start
+    while active_task_count() < 2 {
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(active_task_count(), 2);
+
+    first.cancel();
+    second.cancel();
+    first.wait().unwrap();
+    second.wait().unwrap();
+
+    assert_eq!(active_task_count(), 0);
+}
@@ -0,0 +1,51 @@
+//! Checks for `active_count_for()`, see the `common` module for why this is
+//! its own test binary.
+
+mod common;
+
+use anyhow::Error;
+use common::Spin;
+use ffi_helpers::{
+    task::{active_count_for, CancellationToken, TaskHandle},
+    Task,
+};
+use std::{thread, time::Duration};
+
+#[derive(Debug, Clone, Copy)]
+struct OtherSpin;
+
+impl Task for OtherSpin {
+    type Output = ();
+
+    fn run(&self, cancel_tok: &CancellationToken) -> Result<(), Error> {
+        Spin.run(cancel_tok)
+    }
+}
+
+#[test]
+fn count_running_tasks_by_type() {
+    assert_eq!(active_count_for::<Spin>(), 0);
+    assert_eq!(active_count_for::<OtherSpin>(), 0);
+
+    let handles = vec![
+        TaskHandle::spawn(Spin),
+        TaskHandle::spawn(Spin),
+        TaskHandle::spawn(OtherSpin),
+    ];
+
+    // the background threads may take a moment to start
+    while active_count_for::<Spin>() < 2 || active_count_for::<OtherSpin>() < 1
+    {
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(active_count_for::<Spin>(), 2);
+    assert_eq!(active_count_for::<OtherSpin>(), 1);
+
+    for handle in handles {
+        handle.cancel();
+        handle.wait().unwrap();
+    }
+
+    assert_eq!(active_count_for::<Spin>(), 0);
+    assert_eq!(active_count_for::<OtherSpin>(), 0);
+}